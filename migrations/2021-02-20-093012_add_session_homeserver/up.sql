@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE matrix_session ADD COLUMN homeserver TEXT NOT NULL DEFAULT '';
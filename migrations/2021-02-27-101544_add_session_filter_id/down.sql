@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE matrix_session_old (
+    user_id TEXT NOT NULL PRIMARY KEY,
+    access_token TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    homeserver TEXT NOT NULL DEFAULT ''
+);
+INSERT INTO matrix_session_old SELECT user_id, access_token, device_id, homeserver FROM matrix_session;
+DROP TABLE matrix_session;
+ALTER TABLE matrix_session_old RENAME TO matrix_session;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE room_events (
+    user_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    origin_server_ts BIGINT NOT NULL,
+    json TEXT NOT NULL,
+    prev_batch TEXT,
+    PRIMARY KEY (user_id, event_id)
+);
+CREATE INDEX room_events_by_room ON room_events (user_id, room_id, origin_server_ts);
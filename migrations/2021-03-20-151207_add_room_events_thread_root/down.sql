@@ -0,0 +1,15 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE room_events_old (
+    user_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    origin_server_ts BIGINT NOT NULL,
+    json TEXT NOT NULL,
+    prev_batch TEXT,
+    next_batch TEXT,
+    PRIMARY KEY (user_id, event_id)
+);
+INSERT INTO room_events_old SELECT user_id, event_id, room_id, origin_server_ts, json, prev_batch, next_batch FROM room_events;
+DROP TABLE room_events;
+ALTER TABLE room_events_old RENAME TO room_events;
+CREATE INDEX room_events_by_room ON room_events (user_id, room_id, origin_server_ts);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE room_events ADD COLUMN thread_root TEXT;
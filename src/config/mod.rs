@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{paths::Paths, theme::style::Theme};
+
+/// Settings read from `config.toml`, every value is optional
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub sync: SyncConfig,
+    pub timeline: TimelineConfig,
+    pub appearance: AppearanceConfig,
+    pub device: DeviceConfig,
+    pub notifications: NotificationConfig,
+    pub composer: ComposerConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    /// How long the server may hold a sync request open, in seconds
+    pub timeout: u64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self { timeout: 30 }
+    }
+}
+
+impl SyncConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimelineConfig {
+    /// Amount of messages requested each time older messages are loaded
+    pub backfill_limit: u32,
+}
+
+impl Default for TimelineConfig {
+    fn default() -> Self {
+        Self { backfill_limit: 30 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppearanceConfig {
+    pub theme: Theme,
+    pub font_size: u16,
+}
+
+impl Default for AppearanceConfig {
+    fn default() -> Self {
+        Self {
+            theme: Theme::default(),
+            font_size: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Name other users see for the devices we log in
+    pub display_name: String,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            display_name: String::from("desktop"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// Whether unread counts are shown at all
+    pub enabled: bool,
+    /// Only count messages that mention us
+    pub mentions_only: bool,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mentions_only: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ComposerConfig {
+    /// Send messages written in Markdown as formatted messages
+    pub markdown: bool,
+}
+
+impl Default for ComposerConfig {
+    fn default() -> Self {
+        Self { markdown: true }
+    }
+}
+
+impl Config {
+    /// Reads the configuration file, falling back to the defaults when there is none
+    pub fn load(paths: &Paths) -> Result<Self, anyhow::Error> {
+        let path = paths.config_file();
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let contents =
+            std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let config: Self = toml::from_slice(&contents)
+            .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(
+            (1..=300).contains(&self.sync.timeout),
+            "sync.timeout must be between 1 and 300 seconds, got {}",
+            self.sync.timeout
+        );
+        anyhow::ensure!(
+            (1..=1000).contains(&self.timeline.backfill_limit),
+            "timeline.backfill_limit must be between 1 and 1000, got {}",
+            self.timeline.backfill_limit
+        );
+        anyhow::ensure!(
+            (8..=64).contains(&self.appearance.font_size),
+            "appearance.font_size must be between 8 and 64, got {}",
+            self.appearance.font_size
+        );
+        anyhow::ensure!(
+            !self.device.display_name.trim().is_empty(),
+            "device.display_name can't be empty"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads and validates a configuration file the way `load` does
+    fn parse(contents: &str) -> Result<Config, anyhow::Error> {
+        let config: Config = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn empty_file_uses_the_defaults() {
+        let config = parse("").unwrap();
+        assert_eq!(config.sync.timeout, 30);
+        assert_eq!(config.timeline.backfill_limit, 30);
+        assert_eq!(config.appearance.font_size, 20);
+        assert!(config.composer.markdown);
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn defaults_fill_a_partial_file() {
+        let config = parse(
+            "[sync]\n\
+             timeout = 60\n\
+             [appearance]\n\
+             theme = \"dark\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.sync.timeout(), Duration::from_secs(60));
+        assert_eq!(config.appearance.theme, Theme::Dark);
+        // Missing keys of a table that is there get their defaults too
+        assert_eq!(config.appearance.font_size, 20);
+        assert_eq!(config.timeline.backfill_limit, 30);
+        assert_eq!(config.device.display_name, "desktop");
+        assert!(config.notifications.enabled);
+        assert!(!config.notifications.mentions_only);
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        assert!(parse("[sync]\ntimeout = 0").is_err());
+        assert!(parse("[sync]\ntimeout = 301").is_err());
+        assert!(parse("[sync]\ntimeout = 300").is_ok());
+        assert!(parse("[timeline]\nbackfill_limit = 0").is_err());
+        assert!(parse("[timeline]\nbackfill_limit = 1001").is_err());
+        assert!(parse("[timeline]\nbackfill_limit = 1").is_ok());
+        assert!(parse("[appearance]\nfont_size = 7").is_err());
+        assert!(parse("[appearance]\nfont_size = 65").is_err());
+        assert!(parse("[device]\ndisplay_name = \"  \"").is_err());
+        // Negative numbers don't fit at all
+        assert!(parse("[sync]\ntimeout = -1").is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(parse("[sync]\ntimeuot = 30").is_err());
+        assert!(parse("[colours]\ntheme = \"dark\"").is_err());
+        assert!(parse("verbose = true").is_err());
+        // Values of the wrong type too
+        assert!(parse("[appearance]\ntheme = \"blue\"").is_err());
+        assert!(parse("[composer]\nmarkdown = \"yes\"").is_err());
+    }
+}
@@ -1,11 +1,23 @@
+use anyhow::Context;
 use diesel::prelude::*;
-use dotenv::dotenv;
-use std::env;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
-pub fn establish_connection() -> SqliteConnection {
-    dotenv().ok();
+use crate::paths::Paths;
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+/// Brings the database up to date, once when the application starts
+pub fn run_migrations(paths: &Paths) -> Result<(), anyhow::Error> {
+    let mut conn = establish_connection(paths)?;
+    conn.run_pending_migrations(MIGRATIONS)
+        .map_err(|e| anyhow::anyhow!("Failed to migrate {}: {}", paths.database().display(), e))?;
+    Ok(())
+}
+
+/// Opens the database, which `run_migrations` has already set up
+pub fn establish_connection(paths: &Paths) -> Result<SqliteConnection, anyhow::Error> {
+    let database_path = paths.database();
+    let database_url = database_path.to_string_lossy();
     SqliteConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connection to {}", &database_url))
+        .with_context(|| format!("Failed to open {}", database_url))
 }
@@ -0,0 +1,218 @@
+use std::{convert::TryFrom, time::UNIX_EPOCH};
+
+use crate::schema::room_events;
+use diesel::prelude::*;
+use matrix_sdk::{
+    events::{AnyMessageEvent, AnyRoomEvent},
+    identifiers::{EventId, RoomId, UserId},
+};
+
+use crate::matrix::message::{redact_json, AnyRoomEventExt, CacheUpdate, CachedEvent};
+
+#[derive(Queryable)]
+pub struct StoredEvent {
+    pub user_id: String,
+    pub event_id: String,
+    pub room_id: String,
+    pub origin_server_ts: i64,
+    pub json: String,
+    pub prev_batch: Option<String>,
+    pub next_batch: Option<String>,
+    pub thread_root: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "room_events"]
+pub struct NewEvent<'a> {
+    pub user_id: &'a str,
+    pub event_id: &'a str,
+    pub room_id: &'a str,
+    pub origin_server_ts: i64,
+    pub json: String,
+    pub prev_batch: Option<&'a str>,
+    pub next_batch: Option<&'a str>,
+    pub thread_root: Option<&'a str>,
+}
+
+/// Caches events of a room, `token` being the one to paginate back from the oldest of them
+pub fn add_events(
+    conn: &SqliteConnection,
+    user: &UserId,
+    room: &RoomId,
+    events: &[AnyRoomEvent],
+    token: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    use crate::schema::room_events::dsl::*;
+
+    if events.is_empty() {
+        return Ok(());
+    }
+    let new_events = events
+        .iter()
+        .map(|event| {
+            Ok(NewEvent {
+                user_id: user.as_str(),
+                event_id: event.event_id().as_str(),
+                room_id: room.as_str(),
+                origin_server_ts: event
+                    .origin_server_ts()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|ts| ts.as_millis() as i64)
+                    .unwrap_or_default(),
+                json: serde_json::to_string(event)?,
+                prev_batch: None,
+                next_batch: None,
+                thread_root: None,
+            })
+        })
+        .collect::<Result<Vec<_>, serde_json::Error>>()?;
+
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        // Events we already have don't change, apart from their token
+        diesel::insert_or_ignore_into(room_events)
+            .values(&new_events)
+            .execute(conn)?;
+        let oldest = new_events.iter().min_by_key(|event| event.origin_server_ts);
+        if let (Some(oldest), Some(token)) = (oldest, token) {
+            diesel::update(room_events.find((user.as_str(), oldest.event_id)))
+                .set(prev_batch.eq(token))
+                .execute(conn)?;
+        }
+        // Removed messages shouldn't stay readable in the cache
+        for event in events {
+            if let AnyRoomEvent::Message(AnyMessageEvent::RoomRedaction(redaction)) = event {
+                redact_event(conn, user, &redaction.redacts, event)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Strips a cached event of what `redaction` removed from it
+fn redact_event(
+    conn: &SqliteConnection,
+    user: &UserId,
+    event: &EventId,
+    redaction: &AnyRoomEvent,
+) -> Result<(), anyhow::Error> {
+    use crate::schema::room_events::dsl::*;
+
+    let stored: Option<String> = room_events
+        .find((user.as_str(), event.as_str()))
+        .select(json)
+        .first(conn)
+        .optional()?;
+    if let Some(stored) = stored {
+        let mut redacted: serde_json::Value = serde_json::from_str(&stored)?;
+        redact_json(&mut redacted, &serde_json::to_value(redaction)?);
+        diesel::update(room_events.find((user.as_str(), event.as_str())))
+            .set(json.eq(serde_json::to_string(&redacted)?))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Stores what changed in the timeline of a room
+pub fn update_cache(
+    conn: &SqliteConnection,
+    user: &UserId,
+    room: &RoomId,
+    update: &CacheUpdate,
+) -> Result<(), anyhow::Error> {
+    conn.transaction::<_, anyhow::Error, _>(|| {
+        add_events(conn, user, room, &update.events, None)?;
+        for (event, token) in update.prev_batches.iter() {
+            set_prev_batch(conn, user, event, token.as_deref())?;
+        }
+        for (event, token) in update.next_batches.iter() {
+            set_next_batch(conn, user, event, token.as_deref())?;
+        }
+        Ok(())
+    })
+}
+
+/// Gets the cached events of a room, oldest first.
+///
+/// Events come with the tokens to paginate back from them if we're missing what came before, and
+/// to paginate forward from them if we're missing what came after.
+pub fn get_events(
+    conn: &SqliteConnection,
+    user: &UserId,
+    room: &RoomId,
+) -> Result<Vec<CachedEvent>, anyhow::Error> {
+    use crate::schema::room_events::dsl::*;
+
+    let stored = room_events
+        .filter(user_id.eq(user.as_str()))
+        .filter(room_id.eq(room.as_str()))
+        .order(origin_server_ts.asc())
+        .load::<StoredEvent>(conn)?;
+    Ok(stored
+        .into_iter()
+        // Skip events we can't read anymore instead of losing the whole room
+        .filter_map(|event| {
+            serde_json::from_str(&event.json)
+                .ok()
+                .map(|json| CachedEvent {
+                    event: json,
+                    prev_batch: event.prev_batch,
+                    next_batch: event.next_batch,
+                    thread_root: event
+                        .thread_root
+                        .and_then(|root| EventId::try_from(root).ok()),
+                })
+        })
+        .collect())
+}
+
+/// Sets or clears the token to paginate back from an event
+pub fn set_prev_batch(
+    conn: &SqliteConnection,
+    user: &UserId,
+    event: &EventId,
+    token: Option<&str>,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::room_events::dsl::*;
+
+    diesel::update(room_events.find((user.as_str(), event.as_str())))
+        .set(prev_batch.eq(token))
+        .execute(conn)
+}
+
+/// Sets or clears the token to paginate forward from an event
+pub fn set_next_batch(
+    conn: &SqliteConnection,
+    user: &UserId,
+    event: &EventId,
+    token: Option<&str>,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::room_events::dsl::*;
+
+    diesel::update(room_events.find((user.as_str(), event.as_str())))
+        .set(next_batch.eq(token))
+        .execute(conn)
+}
+
+/// Remembers the root of the thread an event was sent in
+pub fn set_thread_root(
+    conn: &SqliteConnection,
+    user: &UserId,
+    event: &EventId,
+    root: &EventId,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::room_events::dsl::*;
+
+    diesel::update(room_events.find((user.as_str(), event.as_str())))
+        .set(thread_root.eq(root.as_str()))
+        .execute(conn)
+}
+
+/// Removes every cached event of a user
+pub fn remove_events(
+    conn: &SqliteConnection,
+    user: &UserId,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::room_events::dsl::*;
+
+    diesel::delete(room_events.filter(user_id.eq(user.as_str()))).execute(conn)
+}
@@ -1,2 +1,3 @@
 pub mod connection;
-//pub mod session;
+pub mod events;
+pub mod session;
@@ -4,45 +4,59 @@ use crate::schema::matrix_session;
 use diesel::prelude::*;
 use matrix_sdk::identifiers::UserId;
 
+use crate::session::Session;
+
 #[derive(Queryable)]
-pub struct Session {
+pub struct StoredSession {
     pub user_id: String,
     pub access_token: String,
     pub device_id: String,
+    pub homeserver: String,
+    pub filter_id: Option<String>,
 }
 
 #[derive(Insertable, AsChangeset)]
 #[table_name = "matrix_session"]
-pub struct NewSession {
-    pub user_id: String,
-    pub access_token: String,
-    pub device_id: String,
+pub struct NewSession<'a> {
+    pub user_id: &'a str,
+    pub access_token: &'a str,
+    pub device_id: &'a str,
+    pub homeserver: &'a str,
 }
 
-impl Into<matrix_sdk::Session> for Session {
-    fn into(self) -> matrix_sdk::Session {
-        matrix_sdk::Session {
-            access_token: self.access_token,
-            user_id: UserId::try_from(self.user_id).unwrap(),
-            device_id: self.device_id,
-        }
+impl TryFrom<StoredSession> for Session {
+    type Error = anyhow::Error;
+
+    fn try_from(stored: StoredSession) -> Result<Self, Self::Error> {
+        anyhow::ensure!(
+            !stored.homeserver.is_empty(),
+            "No homeserver stored for {}",
+            stored.user_id
+        );
+        Ok(Session {
+            access_token: stored.access_token,
+            user_id: UserId::try_from(stored.user_id)?,
+            device_id: stored.device_id.into(),
+            homeserver: stored.homeserver,
+        })
     }
 }
 
+/// Stores the session, replacing any previous session of the same user
 pub fn add_session(
     conn: &SqliteConnection,
-    session: matrix_sdk::Session,
+    session: &Session,
 ) -> Result<usize, diesel::result::Error> {
-    use crate::schema::matrix_session;
     use crate::schema::matrix_session::dsl::*;
 
     let new_session = NewSession {
-        user_id: session.user_id.to_string(),
-        access_token: session.access_token,
-        device_id: session.device_id.into(),
+        user_id: session.user_id.as_str(),
+        access_token: &session.access_token,
+        device_id: session.device_id.as_str(),
+        homeserver: &session.homeserver,
     };
 
-    diesel::insert_into(matrix_session::table)
+    diesel::insert_into(matrix_session)
         .values(&new_session)
         .on_conflict(user_id)
         .do_update()
@@ -50,8 +64,51 @@ pub fn add_session(
         .execute(conn)
 }
 
-pub fn get_session(conn: &SqliteConnection) -> Result<Session, diesel::result::Error> {
+/// Gets every stored session, skipping the ones that can't be restored
+pub fn get_sessions(conn: &SqliteConnection) -> Result<Vec<Session>, diesel::result::Error> {
+    use crate::schema::matrix_session::dsl::*;
+
+    let stored = matrix_session.load::<StoredSession>(conn)?;
+    Ok(stored
+        .into_iter()
+        .filter_map(|session| Session::try_from(session).ok())
+        .collect())
+}
+
+/// Removes the stored session of a user
+pub fn remove_session(
+    conn: &SqliteConnection,
+    user: &UserId,
+) -> Result<usize, diesel::result::Error> {
     use crate::schema::matrix_session::dsl::*;
 
-    matrix_session.first::<Session>(conn)
+    diesel::delete(matrix_session.filter(user_id.eq(user.as_str()))).execute(conn)
+}
+
+/// Gets the id of the sync filter uploaded for a user, if there is one
+pub fn get_filter_id(
+    conn: &SqliteConnection,
+    user: &UserId,
+) -> Result<Option<String>, diesel::result::Error> {
+    use crate::schema::matrix_session::dsl::*;
+
+    Ok(matrix_session
+        .filter(user_id.eq(user.as_str()))
+        .select(filter_id)
+        .first::<Option<String>>(conn)
+        .optional()?
+        .flatten())
+}
+
+/// Remembers the sync filter uploaded for a user
+pub fn set_filter_id(
+    conn: &SqliteConnection,
+    user: &UserId,
+    filter: &str,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::matrix_session::dsl::*;
+
+    diesel::update(matrix_session.filter(user_id.eq(user.as_str())))
+        .set(filter_id.eq(filter))
+        .execute(conn)
 }
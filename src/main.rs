@@ -6,17 +6,53 @@ extern crate num_derive;
 #[macro_use]
 extern crate diesel;
 
+use config::Config;
+use database::connection::run_migrations;
 use iced::{Application, Settings};
-use screens::elementary::Elementary;
+use paths::Paths;
+use screens::elementary::{Elementary, Flags};
 
+mod config;
 mod database;
 mod matrix;
+mod paths;
+mod rich_text;
 mod schema;
 mod screens;
 mod session;
 mod theme;
+mod utils;
 
 fn main() -> iced::Result {
     tracing_subscriber::fmt::init();
-    Elementary::run(Settings::default())
+
+    let paths = match Paths::from_args(std::env::args().skip(1)) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: elementary-desktop [--data-dir <dir>] [--profile <name>]");
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = paths.create() {
+        eprintln!("Failed to create the data directories, {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = run_migrations(&paths) {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+
+    let config = match Config::load(&paths) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    Elementary::run(Settings {
+        default_text_size: config.appearance.font_size,
+        ..Settings::with_flags(Flags { paths, config })
+    })
 }
@@ -0,0 +1,178 @@
+use std::convert::TryFrom;
+
+use anyhow::Context;
+use matrix_sdk::{
+    identifiers::{ServerName, UserId},
+    Client,
+};
+use url::Url;
+
+use matrix_sdk::api::unversioned::{
+    discover_homeserver::Request as DiscoveryRequest,
+    get_supported_versions::Request as VersionsRequest,
+};
+
+/// What the user told us about where their account lives
+#[derive(Debug, Clone, PartialEq)]
+pub enum HomeserverInput {
+    /// A full homeserver url, used as is
+    Url(Url),
+    /// A server name, which still has to be resolved through .well-known
+    ServerName(Box<ServerName>),
+}
+
+impl HomeserverInput {
+    /// Parses `@alice:example.org`, `example.org` or `https://matrix.example.org`
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let input = input.trim();
+        anyhow::ensure!(!input.is_empty(), "Enter a homeserver or a full user id");
+
+        if input.starts_with('@') {
+            let user_id = UserId::try_from(input)
+                .map_err(|_| anyhow::anyhow!("{} is not a valid user id", input))?;
+            return Ok(Self::ServerName(user_id.server_name().into()));
+        }
+        if input.starts_with("https://") || input.starts_with("http://") {
+            let url = Url::parse(input).with_context(|| format!("{} is not a valid url", input))?;
+            return Ok(Self::Url(url));
+        }
+        // Server names are often copied with the slash of the url they came from
+        let server_name = <&ServerName>::try_from(input.trim_end_matches('/'))
+            .map_err(|_| anyhow::anyhow!("{} is not a valid server name", input))?;
+        Ok(Self::ServerName(server_name.into()))
+    }
+}
+
+/// Looks up the client base url of a server in its `/.well-known/matrix/client`
+async fn well_known(server_name: &ServerName) -> Result<Option<Url>, anyhow::Error> {
+    let server_url = Url::parse(&format!("https://{}", server_name))?;
+    let client = Client::new(server_url)?;
+    // A missing or broken .well-known means the server name is the homeserver itself
+    let response = match client.send(DiscoveryRequest::new(), None).await {
+        Ok(response) => response,
+        Err(_) => return Ok(None),
+    };
+    let base_url = Url::parse(&response.homeserver.base_url).with_context(|| {
+        format!(
+            "{} advertises an invalid homeserver: {}",
+            server_name, response.homeserver.base_url
+        )
+    })?;
+    Ok(Some(base_url))
+}
+
+/// Checks that a Matrix homeserver is reachable at `url`
+async fn validate(url: &Url) -> Result<(), anyhow::Error> {
+    let client = Client::new(url.clone())?;
+    let response = client
+        .send(VersionsRequest::new(), None)
+        .await
+        .with_context(|| format!("No Matrix homeserver found at {}", url))?;
+    anyhow::ensure!(
+        !response.versions.is_empty(),
+        "{} doesn't support any Matrix client API version",
+        url
+    );
+    Ok(())
+}
+
+/// Resolves what the user entered to the base url of their homeserver.
+///
+/// `username` is used to find the server when no homeserver was entered.
+pub async fn resolve_homeserver(homeserver: &str, username: &str) -> Result<Url, anyhow::Error> {
+    let input = if homeserver.trim().is_empty() && username.trim().starts_with('@') {
+        HomeserverInput::parse(username)?
+    } else {
+        HomeserverInput::parse(homeserver)?
+    };
+
+    let url = match input {
+        HomeserverInput::Url(url) => url,
+        HomeserverInput::ServerName(server_name) => match well_known(&server_name).await? {
+            Some(url) => url,
+            None => Url::parse(&format!("https://{}", server_name))?,
+        },
+    };
+    validate(&url).await?;
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(name: &str) -> HomeserverInput {
+        HomeserverInput::ServerName(<&ServerName>::try_from(name).unwrap().into())
+    }
+
+    fn url(url: &str) -> HomeserverInput {
+        HomeserverInput::Url(Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn bare_server_names() {
+        assert_eq!(
+            HomeserverInput::parse("example.org").unwrap(),
+            server("example.org")
+        );
+        assert_eq!(
+            HomeserverInput::parse("  example.org\n").unwrap(),
+            server("example.org")
+        );
+        assert_eq!(
+            HomeserverInput::parse("example.org/").unwrap(),
+            server("example.org")
+        );
+        assert_eq!(
+            HomeserverInput::parse("example.org:8448").unwrap(),
+            server("example.org:8448")
+        );
+        assert_eq!(
+            HomeserverInput::parse("example.org:8448/").unwrap(),
+            server("example.org:8448")
+        );
+    }
+
+    #[test]
+    fn user_ids() {
+        assert_eq!(
+            HomeserverInput::parse("@alice:example.org").unwrap(),
+            server("example.org")
+        );
+        assert_eq!(
+            HomeserverInput::parse("@alice:example.org:8448").unwrap(),
+            server("example.org:8448")
+        );
+        assert!(HomeserverInput::parse("@alice").is_err());
+    }
+
+    #[test]
+    fn full_urls() {
+        assert_eq!(
+            HomeserverInput::parse("https://matrix.example.org").unwrap(),
+            url("https://matrix.example.org/")
+        );
+        assert_eq!(
+            HomeserverInput::parse("https://matrix.example.org/").unwrap(),
+            url("https://matrix.example.org")
+        );
+        assert_eq!(
+            HomeserverInput::parse("http://localhost:8008").unwrap(),
+            url("http://localhost:8008/")
+        );
+        // Homeservers may live under a path
+        assert_eq!(
+            HomeserverInput::parse("https://example.org/matrix/").unwrap(),
+            url("https://example.org/matrix/")
+        );
+        assert!(HomeserverInput::parse("https://").is_err());
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert!(HomeserverInput::parse("").is_err());
+        assert!(HomeserverInput::parse("   ").is_err());
+        assert!(HomeserverInput::parse("not a server").is_err());
+        assert!(HomeserverInput::parse("example.org/matrix").is_err());
+    }
+}
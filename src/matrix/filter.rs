@@ -0,0 +1,74 @@
+use matrix_sdk::{identifiers::UserId, Client, SyncSettings};
+
+use crate::{
+    database::{
+        connection::establish_connection,
+        session::{get_filter_id, set_filter_id},
+    },
+    paths::Paths,
+};
+
+use matrix_sdk::api::r0::{
+    filter::{
+        create_filter::Request as FilterRequest, FilterDefinition, LazyLoadOptions, RoomEventFilter,
+    },
+    sync::sync_events::Filter,
+};
+
+/// Amount of events a room timeline gets in a sync, older ones are backfilled
+const TIMELINE_LIMIT: u32 = 20;
+
+/// Event types we never display, so there's no use in syncing them
+const EXCLUDED_TYPES: [&str; 2] = ["m.call.*", "m.room.aliases"];
+
+/// Filter that lazy loads members, so large rooms don't send their whole member list
+fn filter_definition(not_types: &[String]) -> FilterDefinition<'_> {
+    let lazy_load = LazyLoadOptions::Enabled {
+        include_redundant_members: false,
+    };
+
+    let mut state = RoomEventFilter::default();
+    state.lazy_load_options = lazy_load.clone();
+    state.not_types = not_types;
+
+    let mut timeline = RoomEventFilter::default();
+    timeline.lazy_load_options = lazy_load;
+    timeline.not_types = not_types;
+    timeline.limit = Some(TIMELINE_LIMIT.into());
+
+    let mut definition = FilterDefinition::default();
+    definition.room.state = state;
+    definition.room.timeline = timeline;
+    definition
+}
+
+/// Gets the id of the sync filter of a user, uploading the filter the first time
+pub async fn sync_filter(
+    paths: &Paths,
+    client: &Client,
+    user_id: &UserId,
+) -> Result<String, matrix_sdk::Error> {
+    // Connections can't be held across awaits, so open one each time we need it
+    match establish_connection(paths).and_then(|conn| Ok(get_filter_id(&conn, user_id)?)) {
+        Ok(Some(filter_id)) => return Ok(filter_id),
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Reading the sync filter failed: {:#}", e),
+    }
+
+    let not_types: Vec<String> = EXCLUDED_TYPES.iter().map(|t| String::from(*t)).collect();
+    let request = FilterRequest::new(user_id, filter_definition(&not_types));
+    let filter_id = client.send(request, None).await?.filter_id;
+
+    let stored =
+        establish_connection(paths).and_then(|conn| Ok(set_filter_id(&conn, user_id, &filter_id)?));
+    if let Err(e) = stored {
+        // We'll upload the filter again next time
+        tracing::warn!("Storing the sync filter failed: {:#}", e);
+    }
+    Ok(filter_id)
+}
+
+/// Sync settings using the uploaded filter
+pub fn sync_settings(filter_id: &str) -> SyncSettings<'_> {
+    SyncSettings::new().filter(Filter::FilterId(filter_id))
+}
@@ -1,58 +1,174 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use matrix_sdk::{
     events::{
         room::message::MessageEventContent, AnySyncMessageEvent, AnySyncRoomEvent, AnyToDeviceEvent,
     },
+    identifiers::UserId,
     Client, ClientConfig, LoopCtrl, Sas, SyncSettings,
 };
 use url::Url;
 
-use crate::session::{write_session, Session};
+use crate::{
+    database::{
+        connection::establish_connection,
+        events::remove_events,
+        session::{add_session, remove_session},
+    },
+    paths::Paths,
+    session::Session,
+};
+
+use super::{
+    discovery::resolve_homeserver,
+    filter::{sync_filter, sync_settings},
+    sso::sso_login_token,
+};
+
+use matrix_sdk::api::{
+    error::{Error as ApiError, ErrorKind},
+    r0::session::{
+        get_login_types::{LoginType, Request as LoginTypesRequest},
+        login::{LoginInfo, Request as LoginRequest},
+        logout::Request as LogoutRequest,
+    },
+};
+use matrix_sdk::{FromHttpResponseError, HttpError, ServerError};
 
 pub async fn restore_login(
+    paths: Paths,
     saved_session: Session,
-) -> Result<(Client, matrix_sdk::Session), matrix_sdk::Error> {
-    let homeserver_url = Url::parse(&saved_session.homeserver).unwrap();
+) -> Result<(Client, matrix_sdk::Session), anyhow::Error> {
+    let homeserver_url = Url::parse(&saved_session.homeserver)?;
 
-    let client_config = ClientConfig::new().store_path("./data/config");
+    let client_config = ClientConfig::new().store_path(device_store(&paths, &saved_session)?);
 
+    // Rooms come from the local store, the first sync happens in the background with the
+    // filter stored for the account
     let client = Client::new_with_config(homeserver_url, client_config)?;
     client.restore_login(saved_session.clone().into()).await?;
-    client.sync_once(SyncSettings::new()).await?;
 
     return Ok((client, saved_session.into()));
 }
 
+/// The store of a stored session's device.
+///
+/// Devices logged in before every device got its own store take over the store they used to
+/// share, the old session file only ever held the session that created it.
+fn device_store(paths: &Paths, session: &Session) -> Result<PathBuf, anyhow::Error> {
+    let path = paths.store(&session.user_id, &session.device_id);
+    if path.is_dir() {
+        return Ok(path);
+    }
+    if paths.move_legacy_store(&path)? {
+        return Ok(path);
+    }
+    // An empty store would create new keys for a device the server already has keys of
+    forget_session(paths, &session.clone().into())?;
+    anyhow::bail!(
+        "The encryption keys of {} are missing on this computer, log in again",
+        session.user_id
+    )
+}
+
 pub async fn login(
+    paths: &Paths,
     homeserver: &str,
     user: &str,
     password: &str,
-) -> Result<(Client, Session), matrix_sdk::Error> {
-    let homeserver_url = Url::parse(homeserver).unwrap();
+    device_name: &str,
+) -> Result<(Client, Session), anyhow::Error> {
+    let homeserver_url = resolve_homeserver(homeserver, user).await?;
 
-    let client_config = ClientConfig::new().store_path("./data/config");
+    // We only know which store to use once the server has told us our device id
+    let login_client = Client::new(homeserver_url.clone())?;
+    let response = login_client
+        .login(user, password, None, Some(device_name))
+        .await?;
+    let session = Session {
+        access_token: response.access_token,
+        user_id: response.user_id,
+        device_id: response.device_id,
+        homeserver: homeserver_url.to_string(),
+    };
 
-    let client = Client::new_with_config(homeserver_url, client_config)?;
+    let client = finish_login(paths, homeserver_url, &session).await?;
+    Ok((client, session))
+}
+
+/// Logs in through the homeserver's single sign-on page
+pub async fn login_with_sso(
+    paths: &Paths,
+    homeserver_url: Url,
+    device_name: &str,
+) -> Result<(Client, Session), anyhow::Error> {
+    let token = sso_login_token(&homeserver_url).await?;
 
-    let response = client.login(user, password, None, Some("desktop")).await?;
+    let login_client = Client::new(homeserver_url.clone())?;
+    let mut request = LoginRequest::new(LoginInfo::Token { token: &token });
+    request.initial_device_display_name = Some(device_name);
+    let response = login_client.send(request, None).await?;
     let session = Session {
         access_token: response.access_token,
         user_id: response.user_id,
         device_id: response.device_id,
-        homeserver: String::from(homeserver),
+        homeserver: homeserver_url.to_string(),
     };
 
-    println!("Login: {:#?}", client);
+    let client = finish_login(paths, homeserver_url, &session).await?;
+    Ok((client, session))
+}
+
+/// Login flows offered by a homeserver
+#[derive(Debug, Clone)]
+pub struct LoginFlows {
+    pub homeserver: Url,
+    pub password: bool,
+    pub sso: bool,
+}
+
+pub async fn login_flows(homeserver: &str, user: &str) -> Result<LoginFlows, anyhow::Error> {
+    let homeserver_url = resolve_homeserver(homeserver, user).await?;
+    let client = Client::new(homeserver_url.clone())?;
+    let response = client.send(LoginTypesRequest::new(), None).await?;
+    Ok(LoginFlows {
+        homeserver: homeserver_url,
+        password: response
+            .flows
+            .iter()
+            .any(|flow| matches!(flow, LoginType::Password { .. })),
+        sso: response
+            .flows
+            .iter()
+            .any(|flow| matches!(flow, LoginType::Sso { .. })),
+    })
+}
+
+/// Opens the device store for a freshly created session and stores the session
+pub async fn finish_login(
+    paths: &Paths,
+    homeserver_url: Url,
+    session: &Session,
+) -> Result<Client, anyhow::Error> {
+    let client_config =
+        ClientConfig::new().store_path(paths.store(&session.user_id, &session.device_id));
+    let client = Client::new_with_config(homeserver_url, client_config)?;
+    client.restore_login(session.clone().into()).await?;
 
     //let client_ref = &client;
     //let initial_sync = Arc::new(AtomicBool::from(true));
     //let initial_ref = &initial_sync;
 
-    client.sync_once(SyncSettings::new()).await?;
+    // Store the session first, the filter id is kept next to it
+    add_session(&establish_connection(paths)?, session)?;
+    let filter_id = sync_filter(paths, &client, &session.user_id).await?;
+    client.sync_once(sync_settings(&filter_id)).await?;
 
     //client
     //    .sync_with_callback(SyncSettings::new(), |response| async move {
@@ -146,6 +262,45 @@ pub async fn login(
     //    })
     //    .await;
 
-    write_session(&session)?;
-    Ok((client, session))
+    Ok(client)
+}
+
+/// Invalidates the access token on the server and forgets the device locally
+pub async fn logout(
+    paths: Paths,
+    client: Client,
+    session: matrix_sdk::Session,
+) -> Result<UserId, anyhow::Error> {
+    if let Err(e) = client.send(LogoutRequest::new(), None).await {
+        // Still forget the device, the token is of no use to us anymore
+        tracing::warn!("Server logout failed: {}", e);
+    }
+    forget_session(&paths, &session)?;
+    Ok(session.user_id)
+}
+
+/// Removes the stored session and cached events together with the state and crypto store of
+/// its device
+pub fn forget_session(paths: &Paths, session: &matrix_sdk::Session) -> Result<(), anyhow::Error> {
+    let conn = establish_connection(paths)?;
+    remove_session(&conn, &session.user_id)?;
+    remove_events(&conn, &session.user_id)?;
+    let path = paths.store(&session.user_id, &session.device_id);
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+/// Whether the server rejected our access token, meaning we've been logged out
+pub fn is_unknown_token(error: &matrix_sdk::Error) -> bool {
+    matches!(
+        error,
+        matrix_sdk::Error::Http(HttpError::FromHttpResponse(FromHttpResponseError::Http(
+            ServerError::Known(ApiError {
+                kind: ErrorKind::UnknownToken { .. },
+                ..
+            })
+        )))
+    )
 }
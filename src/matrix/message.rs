@@ -1,16 +1,37 @@
-use std::{collections::HashSet, time::SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::SystemTime,
+};
 
 use anyhow::Error;
 use matrix_sdk::{
     events::{
-        room::message::{MessageEventContent, Relation, TextMessageEventContent},
-        AnyMessageEvent, AnyRoomEvent, AnyStateEvent, MessageEvent,
+        reaction::ReactionEventContent,
+        room::{
+            message::{
+                FormattedBody, MessageEventContent, MessageFormat, Relation,
+                TextMessageEventContent,
+            },
+            relationships::InReplyTo,
+        },
+        AnyMessageEvent, AnyRedactedMessageEvent, AnyRoomEvent, AnyStateEvent, MessageEvent,
     },
-    identifiers::{EventId, ServerName},
+    identifiers::{EventId, RoomId, ServerName, UserId},
 };
+
+use crate::{
+    rich_text::{html, Block},
+    utils::markdown,
+};
+
+pub use matrix_sdk::api::r0::message::get_message_events::Direction;
+use serde_json::json;
 use std::convert::TryFrom;
 use url::Url;
 
+/// Relation type of messages sent in a thread
+const THREAD_REL_TYPE: &str = "m.thread";
+
 pub fn parse_mxc(url: &str) -> Result<(Box<ServerName>, String), Error> {
     let url = Url::parse(&url)?;
     anyhow::ensure!(url.scheme() == "mxc", "Not an mxc url");
@@ -41,6 +62,8 @@ impl AnyMessageEventExt for AnyMessageEvent {
 pub trait AnyRoomEventExt {
     /// Gets the event id of the underlying event
     fn event_id(&self) -> &EventId;
+    /// Gets the room the underlying event was sent in
+    fn room_id(&self) -> &RoomId;
     /// Gets the ´origin_server_ts` member of the underlying event
     fn origin_server_ts(&self) -> SystemTime;
     /// Gets the mxc url in a message event if there is noe
@@ -56,6 +79,14 @@ impl AnyRoomEventExt for AnyRoomEvent {
             AnyRoomEvent::RedactedState(e) => e.event_id(),
         }
     }
+    fn room_id(&self) -> &RoomId {
+        match self {
+            AnyRoomEvent::Message(e) => e.room_id(),
+            AnyRoomEvent::State(e) => e.room_id(),
+            AnyRoomEvent::RedactedMessage(e) => e.room_id(),
+            AnyRoomEvent::RedactedState(e) => e.room_id(),
+        }
+    }
     fn origin_server_ts(&self) -> SystemTime {
         match self {
             AnyRoomEvent::Message(e) => e.origin_server_ts(),
@@ -73,106 +104,1683 @@ impl AnyRoomEventExt for AnyRoomEvent {
     }
 }
 
+/// The event an edit replaces, if the event is an edit
+pub fn replaced_event(event: &AnyRoomEvent) -> Option<&EventId> {
+    match event {
+        AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(MessageEvent {
+            content:
+                MessageEventContent::Text(TextMessageEventContent {
+                    relates_to: Some(Relation::Replacement(replacement)),
+                    ..
+                }),
+            ..
+        })) => Some(&replacement.event_id),
+        _ => None,
+    }
+}
+
+/// The message a message replies to
+pub fn reply_to(content: &MessageEventContent) -> Option<&EventId> {
+    match content {
+        MessageEventContent::Text(TextMessageEventContent {
+            relates_to: Some(Relation::Reply { in_reply_to }),
+            ..
+        }) => Some(&in_reply_to.event_id),
+        _ => None,
+    }
+}
+
+/// Removes the quote of the replied to message that clients put in front of the body of replies
+pub fn strip_reply_fallback(body: &str) -> &str {
+    let mut rest = body;
+    while rest.starts_with('>') {
+        rest = rest.splitn(2, '\n').nth(1).unwrap_or("");
+    }
+    rest.trim_start_matches('\n')
+}
+
+/// Escapes text to be put in HTML
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("<br>"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A reply to `original` with an optional HTML body, quoting the original for clients that
+/// don't support replies
+pub fn reply_content(
+    original: &MessageEvent<MessageEventContent>,
+    reply: String,
+    html: Option<String>,
+) -> MessageEventContent {
+    let quoted = match reply_to(&original.content) {
+        Some(_) => strip_reply_fallback(content_body(&original.content)),
+        None => content_body(&original.content),
+    };
+    let mut body = String::new();
+    for (i, line) in quoted.lines().enumerate() {
+        if i == 0 {
+            body.push_str(&format!("> <{}> {}\n", original.sender, line));
+        } else {
+            body.push_str(&format!("> {}\n", line));
+        }
+    }
+    body.push('\n');
+    body.push_str(&reply);
+    let html = format!(
+        "<mx-reply><blockquote><a href=\"https://matrix.to/#/{}/{}\">In reply to</a> \
+         <a href=\"https://matrix.to/#/{}\">{}</a><br>{}</blockquote></mx-reply>{}",
+        original.room_id,
+        original.event_id,
+        original.sender,
+        original.sender,
+        escape_html(quoted),
+        html.unwrap_or_else(|| escape_html(&reply)),
+    );
+
+    let mut content = TextMessageEventContent::plain(body);
+    content.formatted = Some(FormattedBody::html(html));
+    content.relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo::new(original.event_id.clone()),
+    });
+    MessageEventContent::Text(content)
+}
+
+/// The root of the thread an event was sent in.
+///
+/// The SDK drops relations it doesn't know, so this reads the JSON of the event.
+pub fn thread_root(json: &serde_json::Value) -> Option<EventId> {
+    let relation = json.get("content")?.get("m.relates_to")?;
+    if relation.get("rel_type")?.as_str()? != THREAD_REL_TYPE {
+        return None;
+    }
+    EventId::try_from(relation.get("event_id")?.as_str()?).ok()
+}
+
+/// A message sent in the thread of `root` with an optional HTML body, replying to `latest`, the
+/// newest message of the thread, for clients that don't support threads
+pub fn thread_content(
+    root: &EventId,
+    latest: &EventId,
+    body: String,
+    html: Option<String>,
+) -> serde_json::Value {
+    let mut content = json!({
+        "msgtype": "m.text",
+        "body": body,
+        "m.relates_to": {
+            "rel_type": THREAD_REL_TYPE,
+            "event_id": root.as_str(),
+            "is_falling_back": true,
+            "m.in_reply_to": { "event_id": latest.as_str() },
+        },
+    });
+    if let Some(html) = html {
+        content["format"] = json!("org.matrix.custom.html");
+        content["formatted_body"] = json!(html);
+    }
+    content
+}
+
+/// The HTML formatted body of a message, if it has one
+pub fn formatted_html(content: &MessageEventContent) -> Option<&str> {
+    match content {
+        MessageEventContent::Text(TextMessageEventContent {
+            formatted: Some(formatted),
+            ..
+        }) if matches!(formatted.format, MessageFormat::Html) => Some(&formatted.body),
+        _ => None,
+    }
+}
+
+/// The plain text body of a message
+pub fn content_body(content: &MessageEventContent) -> &str {
+    match content {
+        MessageEventContent::Audio(audio) => &audio.body,
+        MessageEventContent::Emote(emote) => &emote.body,
+        MessageEventContent::File(file) => &file.body,
+        MessageEventContent::Image(image) => &image.body,
+        MessageEventContent::Location(location) => &location.body,
+        MessageEventContent::Notice(notice) => &notice.body,
+        MessageEventContent::ServerNotice(notice) => &notice.body,
+        MessageEventContent::Text(text) => &text.body,
+        MessageEventContent::Video(video) => &video.body,
+        _ => "",
+    }
+}
+
+/// Parses the body of a message for display, as HTML if it's formatted and as Markdown
+/// otherwise. `content` is the latest version of the message
+fn parse_body(
+    message: &MessageEvent<MessageEventContent>,
+    content: &MessageEventContent,
+) -> Option<Vec<Block>> {
+    match (formatted_html(content), content) {
+        (Some(html), _) => Some(html::parse(html)),
+        (None, MessageEventContent::Text(text)) => Some(match reply_to(&message.content) {
+            Some(_) => markdown::parse_text(strip_reply_fallback(&text.body)),
+            None => markdown::parse_text(&text.body),
+        }),
+        _ => None,
+    }
+}
+
+/// Keys of the content of an event type that a redaction keeps
+fn kept_content_keys(event_type: &str) -> &'static [&'static str] {
+    match event_type {
+        "m.room.member" => &["membership"],
+        "m.room.create" => &["creator"],
+        "m.room.join_rules" => &["join_rule"],
+        "m.room.power_levels" => &[
+            "ban",
+            "events",
+            "events_default",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        "m.room.aliases" => &["aliases"],
+        "m.room.history_visibility" => &["history_visibility"],
+        _ => &[],
+    }
+}
+
+/// Removes the content a redaction removes from the JSON of an event, so it can be read back as
+/// redacted
+pub fn redact_json(event: &mut serde_json::Value, redaction: &serde_json::Value) {
+    let keep = kept_content_keys(event["type"].as_str().unwrap_or_default());
+    if let Some(content) = event["content"].as_object() {
+        let kept: serde_json::Map<String, serde_json::Value> = content
+            .iter()
+            .filter(|(key, _)| keep.contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        event["content"] = serde_json::Value::Object(kept);
+    }
+    event["unsigned"] = json!({ "redacted_because": redaction });
+}
+
+/// The content an edit replaces the original content with
+fn new_content(content: &MessageEventContent) -> Option<&MessageEventContent> {
+    match content {
+        MessageEventContent::Text(TextMessageEventContent {
+            new_content: Some(new_content),
+            ..
+        }) => Some(new_content),
+        _ => None,
+    }
+}
+
+/// Who removed a message and why
 #[derive(Clone, Debug)]
-pub struct MessageBuffer {
-    /// The messages we have stored
-    pub messages: Vec<AnyRoomEvent>,
+pub struct Redaction {
+    /// Unknown if the server sent us the message already redacted without saying by whom
+    pub sender: Option<UserId>,
+    pub reason: Option<String>,
+}
+
+/// Events we received without any holes between them
+#[derive(Clone, Debug, Default)]
+pub struct Chunk {
+    /// Events sorted by send time
+    pub events: Vec<AnyRoomEvent>,
+    /// Token to paginate back from the oldest event
+    pub prev_batch: Option<String>,
+    /// Token to paginate forward from the newest event
+    pub next_batch: Option<String>,
+}
+
+/// What the event cache needs to be told after the timeline changed
+#[derive(Clone, Debug, Default)]
+pub struct CacheUpdate {
+    /// Events the timeline didn't have yet
+    pub events: Vec<AnyRoomEvent>,
+    /// Events whose token to paginate back from changed, the cache keeps the token of a chunk
+    /// on its oldest event
+    pub prev_batches: Vec<(EventId, Option<String>)>,
+    /// Events whose token to paginate forward from changed, kept on the newest event of a chunk
+    pub next_batches: Vec<(EventId, Option<String>)>,
+}
+
+/// An event from the cache with the tokens of the chunk it starts or ends
+#[derive(Clone, Debug)]
+pub struct CachedEvent {
+    pub event: AnyRoomEvent,
+    /// Token to paginate back from, if we're missing what came before the event
+    pub prev_batch: Option<String>,
+    /// Token to paginate forward from, only meaningful on the newest event of a chunk
+    pub next_batch: Option<String>,
+    /// Root of the thread the event was sent in
+    pub thread_root: Option<EventId>,
+}
+
+/// Changes between the tokens the cache had and has to have
+fn token_changes(
+    before: HashMap<EventId, String>,
+    mut after: HashMap<EventId, String>,
+) -> Vec<(EventId, Option<String>)> {
+    let mut changes = Vec::new();
+    for (id, token) in before {
+        match after.remove(&id) {
+            Some(new) if new == token => {}
+            new => changes.push((id, new)),
+        }
+    }
+    changes.extend(after.into_iter().map(|(id, token)| (id, Some(token))));
+    changes
+}
+
+/// Replies sent in the thread of a message
+#[derive(Clone, Debug, Default)]
+pub struct Thread {
+    /// Replies we have, oldest first
+    pub replies: Vec<MessageEvent<MessageEventContent>>,
+    /// Token to load older replies from, once we started loading them
+    pub next_batch: Option<String>,
+    /// Whether we asked the server for the replies, we only get new ones from syncs otherwise
+    pub loaded: bool,
+    /// Whether we're awaiting replies from the server
+    pub loading: bool,
+}
+
+impl Thread {
+    /// Whether there are replies we could still load from the server
+    pub fn has_more(&self) -> bool {
+        !self.loaded || self.next_batch.is_some()
+    }
+}
+
+/// Messages missing between two chunks
+#[derive(Clone, Debug)]
+pub struct Gap {
+    /// Token to start filling the gap from
+    pub from: String,
+    pub direction: Direction,
+}
+
+/// Events of a room in the order they were sent, without duplicates and with edits and
+/// redactions applied
+#[derive(Clone, Debug)]
+pub struct Timeline {
+    /// The messages we have stored, oldest chunk first with a gap after each chunk but the last
+    pub chunks: Vec<Chunk>,
     /// Set of event id's we have
     known_ids: HashSet<EventId>,
-    /// Token for the start of the messages we have
-    pub start: Option<String>,
-    /// Token for the end of the messages we have
-    pub end: Option<String>,
+    /// Edits of each edited message, oldest first. The edits stay in the chunks, but aren't
+    /// displayed on their own
+    edits: HashMap<EventId, Vec<MessageEvent<MessageEventContent>>>,
+    /// Redactions of the messages that have been removed, shown instead of the message
+    redactions: HashMap<EventId, Redaction>,
+    /// Reactions to each message, in the order we received them
+    reactions: HashMap<EventId, Vec<MessageEvent<ReactionEventContent>>>,
+    /// Messages outside of the chunks that other messages reply to
+    fetched: HashMap<EventId, MessageEvent<MessageEventContent>>,
+    /// Messages other messages reply to that the server couldn't give us
+    unavailable: HashSet<EventId>,
+    /// Threads by the message they were started from
+    threads: HashMap<EventId, Thread>,
+    /// Root of the thread of each message sent in a thread. The messages stay in the chunks,
+    /// but are only displayed in their thread
+    thread_roots: HashMap<EventId, EventId>,
+    /// Parsed body of each text message, so it isn't parsed again every time it's drawn
+    bodies: HashMap<EventId, Vec<Block>>,
     /// Most recent activity in the room
     pub updated: std::time::SystemTime,
     /// Whether we're awaiting for backfill to be received
     pub loading: bool,
 }
 
-impl MessageBuffer {
-    /// Sorts the messages by send time
-    fn sort(&mut self) {
-        self.messages
-            .sort_unstable_by_key(|msg| msg.origin_server_ts())
-    }
+impl Timeline {
     /// Gets the send time of the most recently sent message
     fn update_time(&mut self) {
-        self.updated = match self.messages.last() {
+        self.updated = match self.events().last() {
             Some(message) => message.origin_server_ts(),
             None => SystemTime::UNIX_EPOCH,
         };
     }
-    fn remove(&mut self, id: &EventId) {
-        self.messages.retain(|e| e.event_id() != id);
-        self.known_ids.remove(&id);
-    }
-    /// Add a message to the buffer.
-    pub fn push(&mut self, event: AnyRoomEvent) {
-        self.known_ids.insert(event.event_id().clone());
-        if let AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(
-            matrix_sdk::events::MessageEvent {
-                content:
-                    MessageEventContent::Text(TextMessageEventContent {
-                        relates_to: Some(Relation::Replacement(ref replacement)),
-                        ..
-                    }),
-                ..
-            },
-        )) = event
-        {
-            self.remove(&replacement.event_id);
+    /// Applies the edits and redactions in `events`
+    fn apply_relations(&mut self, events: &[AnyRoomEvent]) {
+        for event in events.iter() {
+            if let (Some(original), AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(edit))) =
+                (replaced_event(event), event)
+            {
+                let edits = self.edits.entry(original.clone()).or_default();
+                edits.push(edit.clone());
+                edits.sort_by_key(|edit| edit.origin_server_ts);
+            }
+            match event {
+                AnyRoomEvent::Message(AnyMessageEvent::Reaction(reaction)) => {
+                    self.reactions
+                        .entry(reaction.content.relates_to.event_id.clone())
+                        .or_default()
+                        .push(reaction.clone());
+                }
+                AnyRoomEvent::Message(AnyMessageEvent::RoomRedaction(redaction)) => {
+                    self.bodies.remove(&redaction.redacts);
+                    self.redactions.insert(
+                        redaction.redacts.clone(),
+                        Redaction {
+                            sender: Some(redaction.sender.clone()),
+                            reason: redaction.content.reason.clone(),
+                        },
+                    );
+                }
+                // The redaction itself may be in a part of the timeline we don't have
+                AnyRoomEvent::RedactedMessage(AnyRedactedMessageEvent::RoomMessage(message)) => {
+                    let because = message.unsigned.redacted_because.as_ref();
+                    self.redactions
+                        .entry(message.event_id.clone())
+                        .or_insert_with(|| Redaction {
+                            sender: because.map(|redaction| redaction.sender.clone()),
+                            reason: because.and_then(|redaction| redaction.content.reason.clone()),
+                        });
+                }
+                _ => {}
+            }
         }
-        if let AnyRoomEvent::Message(AnyMessageEvent::RoomRedaction(ref redaction)) = event {
-            self.remove(&redaction.redacts);
+    }
+    /// Parses the bodies of the messages in `events`, and again those of the messages edited
+    /// in `events` or whose edits were removed. The relations have to be applied first
+    fn parse_bodies(&mut self, events: &[AnyRoomEvent]) {
+        for event in events {
+            let message = match (replaced_event(event), event) {
+                (Some(original), _) => self.message(original),
+                (None, AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message))) => {
+                    Some(message)
+                }
+                // Removing an edit brings back the previous version
+                (None, AnyRoomEvent::Message(AnyMessageEvent::RoomRedaction(redaction))) => self
+                    .edits
+                    .iter()
+                    .find(|(_, edits)| edits.iter().any(|edit| edit.event_id == redaction.redacts))
+                    .and_then(|(original, _)| self.message(original)),
+                _ => None,
+            };
+            let body = message.and_then(|message| {
+                let blocks = parse_body(message, self.content(message))?;
+                Some((message.event_id.clone(), blocks))
+            });
+            if let Some((id, blocks)) = body {
+                self.bodies.insert(id, blocks);
+            }
         }
-        self.messages.push(event);
-        self.sort();
-        self.update_time();
     }
-    /// Adds several messages to the buffer
-    pub fn append(&mut self, mut events: Vec<AnyRoomEvent>) {
+    /// Drops the events we already have, sorting the rest by send time
+    fn filter_new(&mut self, mut events: Vec<AnyRoomEvent>) -> Vec<AnyRoomEvent> {
         events.retain(|e| !self.known_ids.contains(e.event_id()));
+        self.apply_relations(&events);
+        self.parse_bodies(&events);
         for event in events.iter() {
-            // Handle replacement
-            if let AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(
-                matrix_sdk::events::MessageEvent {
-                    content:
-                        MessageEventContent::Text(TextMessageEventContent {
-                            relates_to: Some(Relation::Replacement(replacement)),
-                            ..
-                        }),
-                    ..
-                },
-            )) = event
+            self.known_ids.insert(event.event_id().clone());
+        }
+        events.sort_by_key(|e| e.origin_server_ts());
+        events
+    }
+    /// Every message we have, oldest first
+    pub fn events(&self) -> impl Iterator<Item = &AnyRoomEvent> {
+        self.chunks.iter().flat_map(|chunk| chunk.events.iter())
+    }
+    /// Finds a message by its event id
+    pub fn message(&self, id: &EventId) -> Option<&MessageEvent<MessageEventContent>> {
+        self.events().find_map(|event| match event {
+            AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message))
+                if &message.event_id == id =>
             {
-                self.remove(&replacement.event_id);
+                Some(message)
             }
-            if let AnyRoomEvent::Message(AnyMessageEvent::RoomRedaction(redaction)) = event {
-                self.remove(&redaction.redacts);
+            _ => None,
+        })
+    }
+    /// Finds the message a reply refers to, which may not be part of the chunks
+    pub fn replied(&self, id: &EventId) -> Option<&MessageEvent<MessageEventContent>> {
+        self.message(id).or_else(|| self.fetched.get(id))
+    }
+    /// Keeps a message fetched on its own because a reply refers to it
+    pub fn add_fetched(&mut self, event: AnyRoomEvent) {
+        match event {
+            AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => {
+                self.unavailable.remove(&message.event_id);
+                self.fetched.insert(message.event_id.clone(), message);
+            }
+            // Anything else can't be quoted either
+            event => {
+                self.unavailable.insert(event.event_id().clone());
             }
-            self.known_ids.insert(event.event_id().clone());
         }
-        self.messages.append(&mut events);
-        self.sort();
+    }
+    /// Remembers that a message a reply refers to couldn't be fetched
+    pub fn add_unavailable(&mut self, id: EventId) {
+        self.unavailable.insert(id);
+    }
+    /// Whether fetching a message a reply refers to failed
+    pub fn is_unavailable(&self, id: &EventId) -> bool {
+        self.unavailable.contains(id)
+    }
+    /// Edits of a message made by its sender, oldest first
+    pub fn edits(
+        &self,
+        message: &MessageEvent<MessageEventContent>,
+    ) -> Vec<&MessageEvent<MessageEventContent>> {
+        self.edits
+            .get(&message.event_id)
+            .map(|edits| {
+                // Only the sender may edit a message, and removed edits don't count
+                edits
+                    .iter()
+                    .filter(|edit| edit.sender == message.sender)
+                    .filter(|edit| !self.redactions.contains_key(&edit.event_id))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    /// Who removed a message and why, if it was removed
+    pub fn redaction(&self, id: &EventId) -> Option<&Redaction> {
+        self.redactions.get(id)
+    }
+    /// Users who reacted to a message, grouped by reaction key
+    pub fn reactions(&self, id: &EventId) -> BTreeMap<&str, Vec<&UserId>> {
+        let mut keys: BTreeMap<&str, Vec<&UserId>> = BTreeMap::new();
+        for reaction in self.reactions.get(id).into_iter().flatten() {
+            // Reactions taken back are redacted
+            if self.redactions.contains_key(&reaction.event_id) {
+                continue;
+            }
+            let senders = keys.entry(&reaction.content.relates_to.emoji).or_default();
+            if !senders.contains(&&reaction.sender) {
+                senders.push(&reaction.sender);
+            }
+        }
+        keys
+    }
+    /// The reaction of `user` to a message with `key`, if it wasn't taken back
+    pub fn own_reaction(&self, id: &EventId, user: &UserId, key: &str) -> Option<&EventId> {
+        self.reactions
+            .get(id)?
+            .iter()
+            .find(|reaction| {
+                &reaction.sender == user
+                    && reaction.content.relates_to.emoji == key
+                    && !self.redactions.contains_key(&reaction.event_id)
+            })
+            .map(|reaction| &reaction.event_id)
+    }
+    /// The parsed body of a text message, with its latest edit
+    pub fn body(&self, id: &EventId) -> Option<&[Block]> {
+        self.bodies.get(id).map(Vec::as_slice)
+    }
+    /// Amount of messages that have been edited
+    pub fn edited(&self) -> usize {
+        self.edits.len()
+    }
+    /// The content of a message after its latest edit
+    pub fn content<'a>(
+        &'a self,
+        message: &'a MessageEvent<MessageEventContent>,
+    ) -> &'a MessageEventContent {
+        self.edits(message)
+            .into_iter()
+            .rev()
+            .find_map(|edit| new_content(&edit.content))
+            .unwrap_or(&message.content)
+    }
+    /// Every version of a message with the time it was made, oldest first
+    pub fn versions<'a>(
+        &'a self,
+        message: &'a MessageEvent<MessageEventContent>,
+    ) -> Vec<(SystemTime, &'a MessageEventContent)> {
+        std::iter::once((message.origin_server_ts, &message.content))
+            .chain(self.edits(message).into_iter().filter_map(|edit| {
+                new_content(&edit.content).map(|content| (edit.origin_server_ts, content))
+            }))
+            .collect()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.chunks.iter().all(|chunk| chunk.events.is_empty())
+    }
+    /// Token to load the messages before the oldest one we have
+    pub fn prev_batch(&self) -> Option<&str> {
+        self.chunks
+            .first()
+            .and_then(|chunk| chunk.prev_batch.as_deref())
+    }
+    /// Sets the token to load older messages from, if we don't have any messages yet
+    pub fn set_prev_batch(&mut self, token: String) {
+        if !self.is_empty() {
+            return;
+        }
+        match self.chunks.first_mut() {
+            Some(chunk) => chunk.prev_batch = Some(token),
+            None => self.chunks.push(Chunk {
+                prev_batch: Some(token),
+                ..Default::default()
+            }),
+        }
+    }
+    /// Sets the token to load the messages before `event` from, if it is still the oldest
+    /// message we have. Returns whether it was set
+    pub fn set_prev_batch_before(&mut self, event: &EventId, token: String) -> bool {
+        match self.chunks.first_mut() {
+            Some(chunk) if chunk.events.first().map(|e| e.event_id()) == Some(event) => {
+                chunk.prev_batch = Some(token);
+                true
+            }
+            _ => false,
+        }
+    }
+    /// The hole before the chunk at `index`, there's none before the oldest chunk
+    pub fn gap(&self, index: usize) -> Option<Gap> {
+        let older = self.chunks.get(index.checked_sub(1)?)?;
+        let newer = self.chunks.get(index)?;
+        // Load the most recent of the missing messages first when we can
+        let (from, direction) = match (&newer.prev_batch, &older.next_batch) {
+            (Some(token), _) => (token.clone(), Direction::Backward),
+            (None, Some(token)) => (token.clone(), Direction::Forward),
+            (None, None) => return None,
+        };
+        Some(Gap { from, direction })
+    }
+    /// Tokens the cache keeps on the oldest and newest event of each chunk
+    fn cached_tokens(&self) -> (HashMap<EventId, String>, HashMap<EventId, String>) {
+        let tokens = |first: bool| {
+            self.chunks
+                .iter()
+                .filter_map(|chunk| {
+                    let (event, token) = if first {
+                        (chunk.events.first()?, &chunk.prev_batch)
+                    } else {
+                        (chunk.events.last()?, &chunk.next_batch)
+                    };
+                    Some((event.event_id().clone(), token.clone()?))
+                })
+                .collect()
+        };
+        (tokens(true), tokens(false))
+    }
+    /// The cache update for `events` being added, given the tokens the cache had before
+    fn cache_update(
+        &self,
+        before: (HashMap<EventId, String>, HashMap<EventId, String>),
+        events: Vec<AnyRoomEvent>,
+    ) -> CacheUpdate {
+        let after = self.cached_tokens();
+        CacheUpdate {
+            events,
+            prev_batches: token_changes(before.0, after.0),
+            next_batches: token_changes(before.1, after.1),
+        }
+    }
+    /// Creates a timeline holding the cached events of a room, oldest first
+    pub fn from_cached(events: Vec<CachedEvent>) -> Self {
+        let mut timeline = Self::default();
+        let mut chunk = Chunk::default();
+        let mut threads = Vec::new();
+        for cached in events {
+            // A token on an event means we're missing what came before it
+            if cached.prev_batch.is_some() {
+                if !chunk.events.is_empty() {
+                    timeline.chunks.push(std::mem::take(&mut chunk));
+                }
+                chunk.prev_batch = cached.prev_batch;
+            }
+            // Only the token of the newest event of the chunk counts
+            chunk.next_batch = cached.next_batch;
+            if let Some(root) = cached.thread_root {
+                threads.push((root, cached.event.clone()));
+            }
+            timeline.known_ids.insert(cached.event.event_id().clone());
+            chunk.events.push(cached.event);
+        }
+        if !chunk.events.is_empty() {
+            timeline.chunks.push(chunk);
+        }
+        let events: Vec<AnyRoomEvent> = timeline.events().cloned().collect();
+        timeline.apply_relations(&events);
+        timeline.parse_bodies(&events);
+        for (root, event) in threads {
+            timeline.add_thread_reply(root, event);
+        }
+        timeline.update_time();
+        timeline
+    }
+    /// Adds the timeline of a room from a sync, returning what the event cache has to store.
+    ///
+    /// A limited timeline skipped messages since the last sync, so it starts a new chunk.
+    pub fn sync(
+        &mut self,
+        events: Vec<AnyRoomEvent>,
+        limited: bool,
+        prev_batch: Option<String>,
+        next_batch: String,
+    ) -> CacheUpdate {
+        let before = self.cached_tokens();
+        // Nothing was skipped if we already have some of the events
+        let overlaps = events.iter().any(|e| self.known_ids.contains(e.event_id()));
+        let events = self.filter_new(events);
+        if (limited && !overlaps) || self.chunks.is_empty() {
+            self.chunks.push(Chunk {
+                prev_batch,
+                ..Default::default()
+            });
+        }
+        let chunk = self.chunks.last_mut().unwrap();
+        chunk.events.extend(events.iter().cloned());
+        // Servers don't always send events in the order they were sent
+        chunk.events.sort_by_key(|e| e.origin_server_ts());
+        chunk.next_batch = Some(next_batch);
         self.update_time();
+        self.cache_update(before, events)
     }
-    /// Whather the message buffer has the room creation event
-    pub fn has_beginning(&self) -> bool {
-        self.messages
+    /// Adds the events of a `/messages` request made from `from`, merging two chunks once the
+    /// gap between them is filled. Returns what the event cache has to store
+    pub fn paginate(
+        &mut self,
+        from: &str,
+        direction: Direction,
+        events: Vec<AnyRoomEvent>,
+        end: Option<String>,
+    ) -> CacheUpdate {
+        let index = match direction {
+            Direction::Backward => self
+                .chunks
+                .iter()
+                .position(|chunk| chunk.prev_batch.as_deref() == Some(from)),
+            Direction::Forward => self
+                .chunks
+                .iter()
+                .position(|chunk| chunk.next_batch.as_deref() == Some(from)),
+        };
+        // The chunk was merged with another one since we asked
+        let index = match index {
+            Some(index) => index,
+            None => return CacheUpdate::default(),
+        };
+        let before = self.cached_tokens();
+        // Running into events we have means we reached the other side of the gap
+        let reached_end = end.is_none()
+            || events.is_empty()
+            || events.iter().any(|e| self.known_ids.contains(e.event_id()));
+        let events = self.filter_new(events);
+
+        match direction {
+            Direction::Backward => {
+                let merge = index > 0 && reached_end;
+                let token = if merge || events.is_empty() {
+                    None
+                } else {
+                    end
+                };
+                let chunk = &mut self.chunks[index];
+                let mut older = events.clone();
+                older.append(&mut chunk.events);
+                chunk.events = older;
+                chunk.prev_batch = token;
+                if merge {
+                    let chunk = self.chunks.remove(index);
+                    let older = &mut self.chunks[index - 1];
+                    older.events.extend(chunk.events);
+                    older.next_batch = chunk.next_batch;
+                }
+            }
+            Direction::Forward => {
+                let merge = index + 1 < self.chunks.len() && reached_end;
+                let chunk = &mut self.chunks[index];
+                if !events.is_empty() {
+                    chunk.events.extend(events.iter().cloned());
+                    chunk.next_batch = end;
+                }
+                if merge {
+                    let newer = self.chunks.remove(index + 1);
+                    let chunk = &mut self.chunks[index];
+                    chunk.events.extend(newer.events);
+                    chunk.next_batch = newer.next_batch;
+                }
+            }
+        }
+        self.update_time();
+        self.cache_update(before, events)
+    }
+    /// Adds a message sent in the thread of `root`
+    pub fn add_thread_reply(&mut self, root: EventId, event: AnyRoomEvent) {
+        let message = match event {
+            AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => message,
+            _ => return,
+        };
+        self.thread_roots
+            .insert(message.event_id.clone(), root.clone());
+        let thread = self.threads.entry(root).or_default();
+        if thread
+            .replies
             .iter()
+            .all(|reply| reply.event_id != message.event_id)
+        {
+            thread.replies.push(message);
+            thread.replies.sort_by_key(|reply| reply.origin_server_ts);
+        }
+    }
+    /// Moves a message we have into the thread of `root`, once we know it was sent in one
+    pub fn set_thread_root(&mut self, id: &EventId, root: EventId) {
+        let event = self.events().find(|event| event.event_id() == id).cloned();
+        match event {
+            Some(event) => self.add_thread_reply(root, event),
+            None => {
+                self.thread_roots.insert(id.clone(), root);
+            }
+        }
+    }
+    /// Adds replies loaded from the server to the thread of `root`, `next_batch` being the
+    /// token to load older ones from
+    pub fn add_thread_page(
+        &mut self,
+        root: EventId,
+        events: Vec<AnyRoomEvent>,
+        next_batch: Option<String>,
+    ) {
+        for event in events {
+            self.add_thread_reply(root.clone(), event);
+        }
+        let thread = self.threads.entry(root).or_default();
+        thread.next_batch = next_batch;
+        thread.loaded = true;
+        thread.loading = false;
+    }
+    /// The thread started from a message, if anyone replied in one
+    pub fn thread(&self, root: &EventId) -> Option<&Thread> {
+        self.threads.get(root)
+    }
+    /// The thread started from a message, creating it when there's none yet
+    pub fn thread_mut(&mut self, root: &EventId) -> &mut Thread {
+        self.threads.entry(root.clone()).or_default()
+    }
+    /// The root of the thread a message was sent in
+    pub fn thread_root_of(&self, id: &EventId) -> Option<&EventId> {
+        self.thread_roots.get(id)
+    }
+    /// Whather the timeline has the room creation event
+    pub fn has_beginning(&self) -> bool {
+        self.events()
             .any(|e| matches!(e, AnyRoomEvent::State(AnyStateEvent::RoomCreate(_))))
     }
 }
 
-impl Default for MessageBuffer {
+impl Default for Timeline {
     fn default() -> Self {
         Self {
-            messages: Default::default(),
+            chunks: Default::default(),
             known_ids: Default::default(),
-            start: None,
-            end: None,
+            edits: Default::default(),
+            redactions: Default::default(),
+            reactions: Default::default(),
+            fetched: Default::default(),
+            unavailable: Default::default(),
+            threads: Default::default(),
+            thread_roots: Default::default(),
+            bodies: Default::default(),
             updated: SystemTime::UNIX_EPOCH,
             loading: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rich_text::{Span, Style};
+    use serde_json::json;
+
+    fn message(id: &str, ts: u64) -> AnyRoomEvent {
+        serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": format!("${}:example.org", id),
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": ts,
+            "content": { "msgtype": "m.text", "body": id },
+        }))
+        .unwrap()
+    }
+
+    fn event_id(id: &str) -> EventId {
+        EventId::try_from(format!("${}:example.org", id)).unwrap()
+    }
+
+    fn ids(timeline: &Timeline) -> Vec<String> {
+        timeline
+            .events()
+            .map(|event| {
+                event.event_id().as_str()[1..]
+                    .split(':')
+                    .next()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sync_sorts_events_sent_out_of_order() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![message("c", 3), message("a", 1), message("b", 2)],
+            false,
+            Some("p1".into()),
+            "n1".into(),
+        );
+        timeline.sync(
+            vec![message("e", 5), message("d", 4)],
+            false,
+            None,
+            "n2".into(),
+        );
+        assert_eq!(ids(&timeline), vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(timeline.chunks.len(), 1);
+        assert_eq!(timeline.chunks[0].next_batch.as_deref(), Some("n2"));
+    }
+
+    #[test]
+    fn sync_drops_duplicates() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![message("a", 1), message("b", 2)],
+            false,
+            None,
+            "n1".into(),
+        );
+        let update = timeline.sync(
+            vec![message("b", 2), message("c", 3)],
+            false,
+            None,
+            "n2".into(),
+        );
+        assert_eq!(ids(&timeline), vec!["a", "b", "c"]);
+        assert_eq!(update.events.len(), 1);
+        assert_eq!(update.events[0].event_id(), &event_id("c"));
+    }
+
+    #[test]
+    fn limited_sync_leaves_a_gap() {
+        let mut timeline = Timeline::default();
+        timeline.sync(vec![message("a", 1)], false, None, "n1".into());
+        timeline.sync(vec![message("z", 26)], true, Some("p2".into()), "n2".into());
+        assert_eq!(timeline.chunks.len(), 2);
+        assert!(timeline.gap(0).is_none());
+        let gap = timeline.gap(1).unwrap();
+        assert_eq!(gap.from, "p2");
+        assert!(matches!(gap.direction, Direction::Backward));
+    }
+
+    #[test]
+    fn overlapping_limited_sync_leaves_no_gap() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![message("a", 1), message("b", 2)],
+            false,
+            None,
+            "n1".into(),
+        );
+        timeline.sync(
+            vec![message("b", 2), message("c", 3)],
+            true,
+            Some("p2".into()),
+            "n2".into(),
+        );
+        assert_eq!(timeline.chunks.len(), 1);
+        assert!(timeline.gap(1).is_none());
+        assert_eq!(ids(&timeline), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn gap_falls_back_to_forward() {
+        let mut timeline = Timeline::default();
+        timeline.sync(vec![message("a", 1)], false, None, "n1".into());
+        timeline.sync(vec![message("z", 26)], true, None, "n2".into());
+        let gap = timeline.gap(1).unwrap();
+        assert_eq!(gap.from, "n1");
+        assert!(matches!(gap.direction, Direction::Forward));
+    }
+
+    #[test]
+    fn backward_fill_merges_chunks_without_duplicates() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![message("a", 1), message("b", 2)],
+            false,
+            None,
+            "n1".into(),
+        );
+        timeline.sync(vec![message("e", 5)], true, Some("p2".into()), "n2".into());
+
+        // Not there yet
+        timeline.paginate(
+            "p2",
+            Direction::Backward,
+            vec![message("d", 4)],
+            Some("p3".into()),
+        );
+        assert_eq!(timeline.chunks.len(), 2);
+        assert_eq!(timeline.gap(1).unwrap().from, "p3");
+
+        // Running into events we have closes the gap
+        timeline.paginate(
+            "p3",
+            Direction::Backward,
+            vec![message("c", 3), message("b", 2)],
+            Some("p4".into()),
+        );
+        assert_eq!(timeline.chunks.len(), 1);
+        assert_eq!(ids(&timeline), vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(timeline.chunks[0].next_batch.as_deref(), Some("n2"));
+    }
+
+    #[test]
+    fn forward_fill_merges_chunks_without_duplicates() {
+        let mut timeline = Timeline::default();
+        timeline.sync(vec![message("a", 1)], false, None, "n1".into());
+        timeline.sync(vec![message("d", 4)], true, None, "n2".into());
+        timeline.paginate(
+            "n1",
+            Direction::Forward,
+            vec![message("b", 2), message("c", 3), message("d", 4)],
+            Some("n3".into()),
+        );
+        assert_eq!(timeline.chunks.len(), 1);
+        assert_eq!(ids(&timeline), vec!["a", "b", "c", "d"]);
+        assert_eq!(timeline.chunks[0].next_batch.as_deref(), Some("n2"));
+    }
+
+    #[test]
+    fn paginating_from_an_unknown_token_does_nothing() {
+        let mut timeline = Timeline::default();
+        timeline.sync(vec![message("a", 1)], false, None, "n1".into());
+        let update = timeline.paginate("gone", Direction::Backward, vec![message("b", 0)], None);
+        assert!(update.events.is_empty());
+        assert_eq!(ids(&timeline), vec!["a"]);
+    }
+
+    #[test]
+    fn cache_update_moves_the_token_to_the_oldest_event() {
+        let mut timeline = Timeline::default();
+        timeline.set_prev_batch("p1".into());
+        let update = timeline.sync(vec![message("b", 2)], false, None, "n1".into());
+        assert_eq!(
+            update.prev_batches,
+            vec![(event_id("b"), Some("p1".into()))]
+        );
+
+        let update = timeline.paginate(
+            "p1",
+            Direction::Backward,
+            vec![message("a", 1)],
+            Some("p0".into()),
+        );
+        let mut tokens = update.prev_batches;
+        tokens.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        assert_eq!(
+            tokens,
+            vec![(event_id("a"), Some("p0".into())), (event_id("b"), None)]
+        );
+    }
+
+    #[test]
+    fn cache_update_moves_the_token_to_the_newest_event() {
+        let mut timeline = Timeline::default();
+        let update = timeline.sync(vec![message("a", 1)], false, None, "n1".into());
+        assert_eq!(
+            update.next_batches,
+            vec![(event_id("a"), Some("n1".into()))]
+        );
+
+        let update = timeline.sync(vec![message("b", 2)], false, None, "n2".into());
+        let mut tokens = update.next_batches;
+        tokens.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        assert_eq!(
+            tokens,
+            vec![(event_id("a"), None), (event_id("b"), Some("n2".into()))]
+        );
+    }
+
+    #[test]
+    fn prev_batch_is_only_guessed_for_an_empty_timeline() {
+        let mut timeline = Timeline::default();
+        timeline.sync(vec![message("b", 2)], false, None, "n1".into());
+        timeline.set_prev_batch("recent".into());
+        assert_eq!(timeline.prev_batch(), None);
+
+        assert!(!timeline.set_prev_batch_before(&event_id("a"), "p0".into()));
+        assert!(timeline.set_prev_batch_before(&event_id("b"), "p1".into()));
+        assert_eq!(timeline.prev_batch(), Some("p1"));
+    }
+
+    #[test]
+    fn failed_fetches_are_remembered() {
+        let mut timeline = Timeline::default();
+        timeline.add_unavailable(event_id("a"));
+        assert!(timeline.is_unavailable(&event_id("a")));
+        assert!(timeline.replied(&event_id("a")).is_none());
+
+        // Trying again later may work
+        timeline.add_fetched(message("a", 1));
+        assert!(!timeline.is_unavailable(&event_id("a")));
+        assert!(timeline.replied(&event_id("a")).is_some());
+    }
+
+    #[test]
+    fn reply_fallback_is_stripped() {
+        assert_eq!(
+            strip_reply_fallback("> <@bob:example.org> hi\n\nhello"),
+            "hello"
+        );
+        assert_eq!(
+            strip_reply_fallback("> <@bob:example.org> one\n> two\n> three\n\nhello\nagain"),
+            "hello\nagain"
+        );
+        // Quotes that don't start the body are part of the message
+        assert_eq!(
+            strip_reply_fallback("hello\n> quoted\n\nbye"),
+            "hello\n> quoted\n\nbye"
+        );
+        assert_eq!(strip_reply_fallback("hello"), "hello");
+    }
+
+    #[test]
+    fn reply_content_quotes_the_original() {
+        let original = match message("a", 1) {
+            AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => message,
+            event => panic!("Not a message: {:?}", event),
+        };
+        let content = reply_content(&original, "<b> & co".into(), None);
+        assert_eq!(reply_to(&content), Some(&event_id("a")));
+        assert_eq!(
+            content_body(&content),
+            "> <@alice:example.org> a\n\n<b> & co"
+        );
+        let html = formatted_html(&content).unwrap();
+        assert!(html.starts_with("<mx-reply><blockquote>"));
+        assert!(html.contains("https://matrix.to/#/!room:example.org/$a:example.org"));
+        assert!(html.ends_with("</blockquote></mx-reply>&lt;b&gt; &amp; co"));
+
+        // A formatted reply keeps its HTML after the quote
+        let content = reply_content(&original, "**hi**".into(), Some("<b>hi</b>".into()));
+        assert!(formatted_html(&content)
+            .unwrap()
+            .ends_with("</mx-reply><b>hi</b>"));
+    }
+
+    #[test]
+    fn replies_to_replies_quote_without_the_fallback() {
+        let original: MessageEvent<MessageEventContent> = serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": "$b:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@bob:example.org",
+            "origin_server_ts": 2,
+            "content": {
+                "msgtype": "m.text",
+                "body": "> <@alice:example.org> a\n\nfirst\nreply",
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$a:example.org" } },
+            },
+        }))
+        .unwrap();
+        let content = reply_content(&original, "second".into(), None);
+        assert_eq!(reply_to(&content), Some(&event_id("b")));
+        assert_eq!(
+            content_body(&content),
+            "> <@bob:example.org> first\n> reply\n\nsecond"
+        );
+        assert_eq!(strip_reply_fallback(content_body(&content)), "second");
+    }
+
+    #[test]
+    fn replies_are_shown_without_the_quote() {
+        let reply = |id: &str, content: serde_json::Value| -> AnyRoomEvent {
+            serde_json::from_value(json!({
+                "type": "m.room.message",
+                "event_id": format!("${}:example.org", id),
+                "room_id": "!room:example.org",
+                "sender": "@bob:example.org",
+                "origin_server_ts": 2,
+                "content": content,
+            }))
+            .unwrap()
+        };
+        let relation = json!({ "m.in_reply_to": { "event_id": "$a:example.org" } });
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![
+                message("a", 1),
+                reply(
+                    "b",
+                    json!({
+                        "msgtype": "m.text",
+                        "body": "> <@alice:example.org> a\n\nhello",
+                        "m.relates_to": relation,
+                    }),
+                ),
+                reply(
+                    "c",
+                    json!({
+                        "msgtype": "m.text",
+                        "body": "> <@alice:example.org> a\n\nhello",
+                        "format": "org.matrix.custom.html",
+                        "formatted_body": "<mx-reply><blockquote>a</blockquote></mx-reply>hello",
+                        "m.relates_to": relation,
+                    }),
+                ),
+            ],
+            false,
+            None,
+            "n1".into(),
+        );
+        assert_eq!(
+            timeline.body(&event_id("b")),
+            Some(&plain_body("hello")[..])
+        );
+        assert_eq!(
+            timeline.body(&event_id("c")),
+            Some(&plain_body("hello")[..])
+        );
+    }
+
+    fn user(name: &str) -> UserId {
+        UserId::try_from(format!("@{}:example.org", name)).unwrap()
+    }
+
+    fn reaction(id: &str, ts: u64, sender: &str, target: &str, key: &str) -> AnyRoomEvent {
+        serde_json::from_value(json!({
+            "type": "m.reaction",
+            "event_id": format!("${}:example.org", id),
+            "room_id": "!room:example.org",
+            "sender": format!("@{}:example.org", sender),
+            "origin_server_ts": ts,
+            "content": {
+                "m.relates_to": {
+                    "rel_type": "m.annotation",
+                    "event_id": format!("${}:example.org", target),
+                    "key": key,
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn redaction(id: &str, ts: u64, sender: &str, redacts: &str) -> AnyRoomEvent {
+        serde_json::from_value(json!({
+            "type": "m.room.redaction",
+            "event_id": format!("${}:example.org", id),
+            "room_id": "!room:example.org",
+            "sender": format!("@{}:example.org", sender),
+            "origin_server_ts": ts,
+            "redacts": format!("${}:example.org", redacts),
+            "content": {},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reactions_are_counted_per_key() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![
+                message("a", 1),
+                reaction("r1", 2, "alice", "a", "👍"),
+                reaction("r2", 3, "bob", "a", "👍"),
+                reaction("r3", 4, "alice", "a", "❤️"),
+                // Reacting twice with the same key counts once
+                reaction("r4", 5, "bob", "a", "👍"),
+            ],
+            false,
+            None,
+            "n1".into(),
+        );
+        let (alice, bob) = (user("alice"), user("bob"));
+        let reactions = timeline.reactions(&event_id("a"));
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions["👍"], vec![&alice, &bob]);
+        assert_eq!(reactions["❤️"], vec![&alice]);
+        assert!(timeline.reactions(&event_id("r1")).is_empty());
+    }
+
+    #[test]
+    fn own_reaction_is_found() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![
+                message("a", 1),
+                reaction("r1", 2, "alice", "a", "👍"),
+                reaction("r2", 3, "bob", "a", "❤️"),
+            ],
+            false,
+            None,
+            "n1".into(),
+        );
+        assert_eq!(
+            timeline.own_reaction(&event_id("a"), &user("alice"), "👍"),
+            Some(&event_id("r1"))
+        );
+        assert_eq!(
+            timeline.own_reaction(&event_id("a"), &user("alice"), "❤️"),
+            None
+        );
+        assert_eq!(
+            timeline.own_reaction(&event_id("a"), &user("bob"), "👍"),
+            None
+        );
+    }
+
+    #[test]
+    fn redacted_reactions_are_removed() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![
+                message("a", 1),
+                reaction("r1", 2, "alice", "a", "👍"),
+                reaction("r2", 3, "bob", "a", "👍"),
+            ],
+            false,
+            None,
+            "n1".into(),
+        );
+        // Taking a reaction back redacts it
+        timeline.sync(
+            vec![redaction("x", 4, "alice", "r1")],
+            false,
+            None,
+            "n2".into(),
+        );
+        let reactions = timeline.reactions(&event_id("a"));
+        assert_eq!(reactions["👍"], vec![&user("bob")]);
+        assert_eq!(
+            timeline.own_reaction(&event_id("a"), &user("alice"), "👍"),
+            None
+        );
+
+        timeline.sync(
+            vec![redaction("y", 5, "bob", "r2")],
+            false,
+            None,
+            "n3".into(),
+        );
+        assert!(timeline.reactions(&event_id("a")).is_empty());
+    }
+
+    #[test]
+    fn bodies_are_parsed_again_when_edited() {
+        let paragraph = |text: &str, bold: bool| {
+            Block::Paragraph(vec![Span {
+                text: text.to_owned(),
+                style: Style {
+                    bold,
+                    ..Default::default()
+                },
+            }])
+        };
+        let mut timeline = Timeline::default();
+        timeline.sync(vec![message("a", 1)], false, None, String::from("s1"));
+        assert_eq!(
+            timeline.body(&event_id("a")),
+            Some(&[paragraph("a", false)][..])
+        );
+
+        let edit = serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": "$edit:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 2,
+            "content": {
+                "msgtype": "m.text",
+                "body": "* **b**",
+                "m.new_content": { "msgtype": "m.text", "body": "**b**" },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": "$a:example.org" },
+            },
+        }))
+        .unwrap();
+        timeline.sync(vec![edit], false, None, String::from("s2"));
+        assert_eq!(
+            timeline.body(&event_id("a")),
+            Some(&[paragraph("b", true)][..])
+        );
+        // Edits are only shown in place of the message
+        assert!(timeline.body(&event_id("edit")).is_none());
+    }
+
+    fn edit(id: &str, ts: u64, sender: &str, original: &str, body: &str) -> AnyRoomEvent {
+        serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": format!("${}:example.org", id),
+            "room_id": "!room:example.org",
+            "sender": format!("@{}:example.org", sender),
+            "origin_server_ts": ts,
+            "content": {
+                "msgtype": "m.text",
+                "body": format!("* {}", body),
+                "m.new_content": { "msgtype": "m.text", "body": body },
+                "m.relates_to": {
+                    "rel_type": "m.replace",
+                    "event_id": format!("${}:example.org", original),
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    /// Parsed body of an unformatted message
+    fn plain_body(text: &str) -> Vec<Block> {
+        vec![Block::Paragraph(vec![Span {
+            text: text.to_owned(),
+            style: Style::default(),
+        }])]
+    }
+
+    /// Body of a message after its latest edit
+    fn latest_body(timeline: &Timeline, id: &str) -> String {
+        let message = timeline.message(&event_id(id)).unwrap();
+        content_body(timeline.content(message)).to_owned()
+    }
+
+    #[test]
+    fn edits_are_applied_in_the_order_they_were_sent() {
+        let mut timeline = Timeline::default();
+        timeline.sync(vec![message("a", 1)], false, None, "n1".into());
+        timeline.sync(
+            vec![edit("e2", 3, "alice", "a", "third")],
+            false,
+            None,
+            "n2".into(),
+        );
+        // An older edit arriving late doesn't replace the newer one
+        timeline.sync(
+            vec![edit("e1", 2, "alice", "a", "second")],
+            false,
+            None,
+            "n3".into(),
+        );
+        assert_eq!(latest_body(&timeline, "a"), "third");
+        let message = timeline.message(&event_id("a")).unwrap();
+        let versions: Vec<&str> = timeline
+            .versions(message)
+            .into_iter()
+            .map(|(_, content)| content_body(content))
+            .collect();
+        assert_eq!(versions, vec!["a", "second", "third"]);
+        assert_eq!(
+            timeline.body(&event_id("a")),
+            Some(&plain_body("third")[..])
+        );
+    }
+
+    #[test]
+    fn edits_by_other_senders_are_ignored() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![message("a", 1), edit("e1", 2, "mallory", "a", "forged")],
+            false,
+            None,
+            "n1".into(),
+        );
+        assert_eq!(latest_body(&timeline, "a"), "a");
+        let message = timeline.message(&event_id("a")).unwrap();
+        assert!(timeline.edits(message).is_empty());
+        assert_eq!(timeline.versions(message).len(), 1);
+        assert_eq!(timeline.body(&event_id("a")), Some(&plain_body("a")[..]));
+    }
+
+    #[test]
+    fn removed_edits_bring_back_the_previous_version() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![message("a", 1), edit("e1", 2, "alice", "a", "second")],
+            false,
+            None,
+            "n1".into(),
+        );
+        timeline.sync(
+            vec![redaction("x", 3, "alice", "e1")],
+            false,
+            None,
+            "n2".into(),
+        );
+        assert_eq!(latest_body(&timeline, "a"), "a");
+        assert_eq!(timeline.body(&event_id("a")), Some(&plain_body("a")[..]));
+    }
+
+    fn redacted_message(id: &str, ts: u64) -> AnyRoomEvent {
+        serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": format!("${}:example.org", id),
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": ts,
+            "content": {},
+            "unsigned": {
+                "redacted_because": {
+                    "type": "m.room.redaction",
+                    "event_id": "$x:example.org",
+                    "room_id": "!room:example.org",
+                    "sender": "@bob:example.org",
+                    "origin_server_ts": ts + 1,
+                    "redacts": format!("${}:example.org", id),
+                    "content": { "reason": "spam" },
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn edits_of_redacted_messages_are_not_shown() {
+        let mut timeline = Timeline::default();
+        timeline.sync(
+            vec![
+                redacted_message("a", 1),
+                edit("e1", 3, "alice", "a", "second"),
+            ],
+            false,
+            None,
+            "n1".into(),
+        );
+        // Only the tombstone is left to show
+        assert!(timeline.message(&event_id("a")).is_none());
+        assert!(timeline.body(&event_id("a")).is_none());
+        let redaction = timeline.redaction(&event_id("a")).unwrap();
+        assert_eq!(redaction.sender, Some(user("bob")));
+        assert_eq!(redaction.reason.as_deref(), Some("spam"));
+    }
+
+    #[test]
+    fn redactions_leave_a_tombstone() {
+        // The redaction comes in a sync after the message
+        let mut timeline = Timeline::default();
+        timeline.sync(vec![message("a", 1)], false, None, "n1".into());
+        timeline.sync(
+            vec![redaction("x", 2, "bob", "a")],
+            false,
+            None,
+            "n2".into(),
+        );
+        let redaction = timeline.redaction(&event_id("a")).unwrap();
+        assert_eq!(redaction.sender, Some(user("bob")));
+        assert_eq!(redaction.reason, None);
+        assert!(timeline.body(&event_id("a")).is_none());
+
+        // The server sends old messages already redacted
+        let mut timeline = Timeline::default();
+        timeline.sync(vec![message("b", 3)], true, Some("p1".into()), "n1".into());
+        timeline.paginate(
+            "p1",
+            Direction::Backward,
+            vec![redacted_message("a", 1)],
+            None,
+        );
+        assert_eq!(ids(&timeline), vec!["a", "b"]);
+        let redaction = timeline.redaction(&event_id("a")).unwrap();
+        assert_eq!(redaction.sender, Some(user("bob")));
+        assert_eq!(redaction.reason.as_deref(), Some("spam"));
+    }
+
+    #[test]
+    fn redacted_json_is_read_back_as_a_tombstone() {
+        let because = serde_json::to_value(redaction("x", 2, "bob", "a")).unwrap();
+        let mut json = serde_json::to_value(message("a", 1)).unwrap();
+        redact_json(&mut json, &because);
+        assert_eq!(json["content"], json!({}));
+        let event: AnyRoomEvent = serde_json::from_value(json).unwrap();
+        assert!(matches!(event, AnyRoomEvent::RedactedMessage(_)));
+
+        let timeline = Timeline::from_cached(vec![cached(event, None, None)]);
+        assert!(timeline.message(&event_id("a")).is_none());
+        assert!(timeline.body(&event_id("a")).is_none());
+        let redaction = timeline.redaction(&event_id("a")).unwrap();
+        assert_eq!(redaction.sender, Some(user("bob")));
+
+        // Some state has to stay for the room to make sense
+        let mut member = json!({
+            "type": "m.room.member",
+            "content": { "membership": "join", "displayname": "Bob" },
+        });
+        redact_json(&mut member, &because);
+        assert_eq!(member["content"], json!({ "membership": "join" }));
+    }
+
+    fn cached(
+        event: AnyRoomEvent,
+        prev_batch: Option<&str>,
+        next_batch: Option<&str>,
+    ) -> CachedEvent {
+        CachedEvent {
+            event,
+            prev_batch: prev_batch.map(str::to_owned),
+            next_batch: next_batch.map(str::to_owned),
+            thread_root: None,
+        }
+    }
+
+    #[test]
+    fn from_cached_rebuilds_chunks() {
+        let timeline = Timeline::from_cached(vec![
+            cached(message("a", 1), Some("p1"), Some("stale")),
+            cached(message("b", 2), None, Some("n1")),
+            cached(message("y", 25), Some("p2"), None),
+            cached(message("z", 26), None, Some("n2")),
+        ]);
+        assert_eq!(timeline.chunks.len(), 2);
+        assert_eq!(timeline.prev_batch(), Some("p1"));
+        assert_eq!(timeline.chunks[0].next_batch.as_deref(), Some("n1"));
+        assert_eq!(timeline.chunks[1].next_batch.as_deref(), Some("n2"));
+        assert_eq!(timeline.gap(1).unwrap().from, "p2");
+        assert_eq!(ids(&timeline), vec!["a", "b", "y", "z"]);
+
+        // Cached events aren't added again
+        let mut timeline = timeline;
+        let update = timeline.sync(vec![message("z", 26)], false, None, "n3".into());
+        assert!(update.events.is_empty());
+        assert!(update.prev_batches.is_empty());
+        assert_eq!(
+            update.next_batches,
+            vec![(event_id("z"), Some("n3".into()))]
+        );
+    }
+
+    fn thread_reply(id: &str, ts: u64, root: &str) -> serde_json::Value {
+        json!({
+            "type": "m.room.message",
+            "event_id": format!("${}:example.org", id),
+            "room_id": "!room:example.org",
+            "sender": "@bob:example.org",
+            "origin_server_ts": ts,
+            "content": {
+                "msgtype": "m.text",
+                "body": id,
+                "m.relates_to": {
+                    "rel_type": "m.thread",
+                    "event_id": format!("${}:example.org", root),
+                    "is_falling_back": true,
+                    "m.in_reply_to": { "event_id": format!("${}:example.org", root) },
+                },
+            },
+        })
+    }
+
+    #[test]
+    fn thread_root_is_read_from_the_json() {
+        let reply = thread_reply("b", 2, "a");
+        assert_eq!(thread_root(&reply), Some(event_id("a")));
+
+        let mut annotation = thread_reply("c", 3, "a");
+        annotation["content"]["m.relates_to"]["rel_type"] = json!("m.annotation");
+        assert_eq!(thread_root(&annotation), None);
+        assert_eq!(thread_root(&json!({ "content": { "body": "a" } })), None);
+    }
+
+    #[test]
+    fn thread_content_falls_back_to_a_reply() {
+        let content = thread_content(
+            &event_id("a"),
+            &event_id("c"),
+            "hi".into(),
+            Some("<b>hi</b>".into()),
+        );
+        let relation = &content["m.relates_to"];
+        assert_eq!(relation["rel_type"], "m.thread");
+        assert_eq!(relation["event_id"], "$a:example.org");
+        assert_eq!(relation["m.in_reply_to"]["event_id"], "$c:example.org");
+        assert_eq!(content["body"], "hi");
+        assert_eq!(content["formatted_body"], "<b>hi</b>");
+        assert_eq!(content["format"], "org.matrix.custom.html");
+
+        let plain = thread_content(&event_id("a"), &event_id("a"), "hi".into(), None);
+        assert!(plain.get("formatted_body").is_none());
+    }
+
+    #[test]
+    fn thread_replies_are_merged_in_order() {
+        let mut timeline = Timeline::default();
+        let synced: AnyRoomEvent = serde_json::from_value(thread_reply("c", 3, "a")).unwrap();
+        timeline.sync(vec![message("a", 1), synced], false, None, "n1".into());
+        timeline.set_thread_root(&event_id("c"), event_id("a"));
+        assert_eq!(
+            timeline.thread_root_of(&event_id("c")),
+            Some(&event_id("a"))
+        );
+        assert!(timeline.thread(&event_id("a")).unwrap().has_more());
+
+        // The server sends the newest replies first
+        let page = vec![
+            serde_json::from_value(thread_reply("c", 3, "a")).unwrap(),
+            serde_json::from_value(thread_reply("b", 2, "a")).unwrap(),
+        ];
+        timeline.add_thread_page(event_id("a"), page, None);
+        let thread = timeline.thread(&event_id("a")).unwrap();
+        let replies: Vec<&str> = thread.replies.iter().map(|r| r.event_id.as_str()).collect();
+        assert_eq!(replies, vec!["$b:example.org", "$c:example.org"]);
+        assert!(!thread.has_more());
+        assert_eq!(
+            timeline.thread_root_of(&event_id("b")),
+            Some(&event_id("a"))
+        );
+    }
+
+    #[test]
+    fn from_cached_restores_threads() {
+        let reply: AnyRoomEvent = serde_json::from_value(thread_reply("b", 2, "a")).unwrap();
+        let mut cached_reply = cached(reply, None, Some("n1"));
+        cached_reply.thread_root = Some(event_id("a"));
+        let timeline =
+            Timeline::from_cached(vec![cached(message("a", 1), None, None), cached_reply]);
+        assert_eq!(timeline.thread(&event_id("a")).unwrap().replies.len(), 1);
+        assert_eq!(
+            timeline.thread_root_of(&event_id("b")),
+            Some(&event_id("a"))
+        );
+    }
+}
@@ -1,4 +1,9 @@
+pub mod discovery;
+pub mod filter;
 pub mod login;
 pub mod message;
+pub mod permalink;
+pub mod register;
 pub mod room;
+pub mod sso;
 pub mod subscriber;
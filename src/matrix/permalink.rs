@@ -0,0 +1,172 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::{EventId, RoomAliasId, RoomId, UserId};
+
+const MATRIX_TO: [&str; 2] = ["https://matrix.to/#/", "http://matrix.to/#/"];
+
+/// What a matrix.to link points to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Permalink {
+    User(UserId),
+    /// A room or an event in it
+    Room(RoomId, Option<EventId>),
+    Alias(RoomAliasId, Option<EventId>),
+}
+
+/// Reads a matrix.to link
+pub fn parse(url: &str) -> Option<Permalink> {
+    let path = MATRIX_TO
+        .iter()
+        .find_map(|prefix| url.strip_prefix(prefix))?;
+    // Routing parameters like `via` don't matter to us
+    let path = path.split('?').next()?;
+    let mut parts = path.split('/');
+    let target = percent_decode(parts.next()?)?;
+    let event = match parts.next() {
+        Some(event) if !event.is_empty() => Some(EventId::try_from(percent_decode(event)?).ok()?),
+        _ => None,
+    };
+    match target.chars().next()? {
+        '@' => Some(Permalink::User(UserId::try_from(target).ok()?)),
+        '!' => Some(Permalink::Room(RoomId::try_from(target).ok()?, event)),
+        '#' => Some(Permalink::Alias(RoomAliasId::try_from(target).ok()?, event)),
+        _ => None,
+    }
+}
+
+/// Decodes the `%XX` escapes in a part of a link
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(id: &str) -> RoomId {
+        RoomId::try_from(id).unwrap()
+    }
+
+    fn alias(alias: &str) -> RoomAliasId {
+        RoomAliasId::try_from(alias).unwrap()
+    }
+
+    fn event(id: &str) -> EventId {
+        EventId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn users() {
+        assert_eq!(
+            parse("https://matrix.to/#/@alice:example.org"),
+            Some(Permalink::User(
+                UserId::try_from("@alice:example.org").unwrap()
+            ))
+        );
+        assert_eq!(
+            parse("http://matrix.to/#/%40alice%3Aexample.org"),
+            Some(Permalink::User(
+                UserId::try_from("@alice:example.org").unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn rooms_and_aliases() {
+        assert_eq!(
+            parse("https://matrix.to/#/!room:example.org"),
+            Some(Permalink::Room(room("!room:example.org"), None))
+        );
+        assert_eq!(
+            parse("https://matrix.to/#/%21room%3Aexample.org"),
+            Some(Permalink::Room(room("!room:example.org"), None))
+        );
+        assert_eq!(
+            parse("https://matrix.to/#/#rust:example.org"),
+            Some(Permalink::Alias(alias("#rust:example.org"), None))
+        );
+        assert_eq!(
+            parse("https://matrix.to/#/%23rust%3Aexample.org"),
+            Some(Permalink::Alias(alias("#rust:example.org"), None))
+        );
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(
+            parse("https://matrix.to/#/!room:example.org/$event:example.org"),
+            Some(Permalink::Room(
+                room("!room:example.org"),
+                Some(event("$event:example.org"))
+            ))
+        );
+        assert_eq!(
+            parse("https://matrix.to/#/%23rust%3Aexample.org/%24event%3Aexample.org"),
+            Some(Permalink::Alias(
+                alias("#rust:example.org"),
+                Some(event("$event:example.org"))
+            ))
+        );
+        // A trailing slash doesn't point to an event
+        assert_eq!(
+            parse("https://matrix.to/#/!room:example.org/"),
+            Some(Permalink::Room(room("!room:example.org"), None))
+        );
+    }
+
+    #[test]
+    fn routing_parameters_are_ignored() {
+        assert_eq!(
+            parse("https://matrix.to/#/!room:example.org?via=example.org&via=other.org"),
+            Some(Permalink::Room(room("!room:example.org"), None))
+        );
+        assert_eq!(
+            parse("https://matrix.to/#/!room:example.org/$event:example.org?via=example.org"),
+            Some(Permalink::Room(
+                room("!room:example.org"),
+                Some(event("$event:example.org"))
+            ))
+        );
+    }
+
+    #[test]
+    fn malformed_links() {
+        // Not matrix.to
+        assert_eq!(parse("https://example.org/#/@alice:example.org"), None);
+        assert_eq!(parse("@alice:example.org"), None);
+        // Nothing or something unknown to point to
+        assert_eq!(parse("https://matrix.to/#/"), None);
+        assert_eq!(parse("https://matrix.to/#/+group:example.org"), None);
+        // Invalid identifiers
+        assert_eq!(parse("https://matrix.to/#/@alice"), None);
+        assert_eq!(
+            parse("https://matrix.to/#/!room:example.org/not-an-event"),
+            None
+        );
+        // Broken escapes
+        assert_eq!(parse("https://matrix.to/#/%4"), None);
+        assert_eq!(parse("https://matrix.to/#/%zz"), None);
+        assert_eq!(parse("https://matrix.to/#/%ff%fe"), None);
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b").as_deref(), Some("a b"));
+        assert_eq!(percent_decode("%E2%9C%93").as_deref(), Some("✓"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%g0"), None);
+    }
+}
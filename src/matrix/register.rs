@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+
+use matrix_sdk::{Client, FromHttpResponseError, HttpError, ServerError};
+use serde_json::Value as JsonValue;
+use url::Url;
+
+use crate::{paths::Paths, session::Session};
+
+use super::{discovery::resolve_homeserver, login::finish_login};
+
+use matrix_sdk::api::r0::{
+    account::register::Request as RegisterRequest,
+    uiaa::{AuthData, UiaaInfo, UiaaResponse},
+};
+
+const DUMMY: &str = "m.login.dummy";
+const TERMS: &str = "m.login.terms";
+const REGISTRATION_TOKEN: &str = "m.login.registration_token";
+const UNSTABLE_REGISTRATION_TOKEN: &str = "org.matrix.msc3231.login.registration_token";
+
+/// Authentication stages we know how to complete
+const SUPPORTED_STAGES: [&str; 4] = [
+    DUMMY,
+    TERMS,
+    REGISTRATION_TOKEN,
+    UNSTABLE_REGISTRATION_TOKEN,
+];
+
+/// A policy the user has to agree to before registering
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub name: String,
+    pub url: String,
+}
+
+/// What a homeserver asks of us to create an account
+#[derive(Debug, Clone)]
+pub struct RegistrationInfo {
+    pub homeserver: Url,
+    /// Stages of the first flow we fully support, empty if there is none
+    pub stages: Vec<String>,
+    /// Stages we don't support, from flows we can't complete
+    pub unsupported: Vec<String>,
+    pub policies: Vec<Policy>,
+}
+
+impl RegistrationInfo {
+    /// Whether we can register on the server at all
+    pub fn supported(&self) -> bool {
+        !self.stages.is_empty()
+    }
+
+    pub fn needs_token(&self) -> bool {
+        self.stages
+            .iter()
+            .any(|stage| stage == REGISTRATION_TOKEN || stage == UNSTABLE_REGISTRATION_TOKEN)
+    }
+
+    pub fn needs_terms(&self) -> bool {
+        self.stages.iter().any(|stage| stage == TERMS)
+    }
+}
+
+/// What the user entered on the registration form
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub username: String,
+    pub password: String,
+    pub token: String,
+    pub accepted_terms: bool,
+}
+
+/// Gets the interactive auth info out of a register error, if the server sent one
+fn uiaa_info(error: matrix_sdk::Error) -> Result<UiaaInfo, anyhow::Error> {
+    match error {
+        matrix_sdk::Error::Http(HttpError::UiaaError(FromHttpResponseError::Http(
+            ServerError::Known(UiaaResponse::AuthResponse(info)),
+        ))) => Ok(info),
+        error => Err(error.into()),
+    }
+}
+
+/// Reads the policies of the terms stage from the auth params
+fn policies(info: &UiaaInfo) -> Vec<Policy> {
+    let params: JsonValue = serde_json::from_str(info.params.get()).unwrap_or_default();
+    let policies = match params[TERMS]["policies"].as_object() {
+        Some(policies) => policies,
+        None => return Vec::new(),
+    };
+    policies
+        .values()
+        .filter_map(|policy| {
+            // Prefer english, otherwise take whatever language comes first
+            let translation = policy
+                .get("en")
+                .or_else(|| policy.as_object()?.values().find(|v| v.is_object()))?;
+            Some(Policy {
+                name: translation["name"].as_str()?.to_owned(),
+                url: translation["url"].as_str()?.to_owned(),
+            })
+        })
+        .collect()
+}
+
+fn registration_info(homeserver: Url, info: &UiaaInfo) -> RegistrationInfo {
+    let supported = |stage: &String| SUPPORTED_STAGES.contains(&stage.as_str());
+    let stages = info
+        .flows
+        .iter()
+        .find(|flow| flow.stages.iter().all(supported))
+        .map(|flow| flow.stages.clone())
+        .unwrap_or_default();
+    let mut unsupported: Vec<String> = info
+        .flows
+        .iter()
+        .flat_map(|flow| flow.stages.iter())
+        .filter(|stage| !supported(stage))
+        .cloned()
+        .collect();
+    unsupported.sort();
+    unsupported.dedup();
+
+    RegistrationInfo {
+        homeserver,
+        stages,
+        unsupported,
+        policies: policies(info),
+    }
+}
+
+/// Asks the homeserver which authentication stages registering takes
+pub async fn registration_flows(homeserver: &str) -> Result<RegistrationInfo, anyhow::Error> {
+    let homeserver_url = resolve_homeserver(homeserver, "").await?;
+    let client = Client::new(homeserver_url.clone())?;
+    // A request without auth is answered with the flows the server offers
+    match client.register(RegisterRequest::new()).await {
+        Ok(_) => anyhow::bail!("The homeserver registered an account without any details"),
+        Err(e) => Ok(registration_info(homeserver_url, &uiaa_info(e)?)),
+    }
+}
+
+/// Creates an account, completing every auth stage of the supported flow
+pub async fn register(
+    paths: &Paths,
+    info: RegistrationInfo,
+    registration: Registration,
+    device_name: &str,
+) -> Result<(Client, Session), anyhow::Error> {
+    anyhow::ensure!(
+        info.supported(),
+        "This homeserver requires registration steps we don't support: {}",
+        info.unsupported.join(", ")
+    );
+    anyhow::ensure!(
+        !info.needs_terms() || registration.accepted_terms,
+        "Accept the terms of the homeserver to register"
+    );
+    anyhow::ensure!(
+        !info.needs_token() || !registration.token.trim().is_empty(),
+        "This homeserver requires a registration token"
+    );
+
+    let client = Client::new(info.homeserver.clone())?;
+    let mut uiaa_session: Option<String> = None;
+    let mut completed: Vec<String> = Vec::new();
+
+    let response = loop {
+        let stage = info
+            .stages
+            .iter()
+            .find(|stage| !completed.contains(stage))
+            .cloned();
+        let mut auth_parameters = BTreeMap::new();
+        if stage.as_deref() == Some(REGISTRATION_TOKEN)
+            || stage.as_deref() == Some(UNSTABLE_REGISTRATION_TOKEN)
+        {
+            auth_parameters.insert(
+                String::from("token"),
+                JsonValue::from(registration.token.trim()),
+            );
+        }
+
+        let mut request = RegisterRequest::new();
+        request.username = Some(&registration.username);
+        request.password = Some(&registration.password);
+        request.initial_device_display_name = Some(device_name);
+        // The first request only opens the auth session
+        if uiaa_session.is_some() {
+            request.auth = stage.as_deref().map(|kind| AuthData::DirectRequest {
+                kind,
+                session: uiaa_session.as_deref(),
+                auth_parameters,
+            });
+        }
+
+        match client.register(request).await {
+            Ok(response) => break response,
+            Err(e) => {
+                let auth_info = uiaa_info(e)?;
+                if let Some(error) = auth_info.auth_error {
+                    anyhow::bail!("{}", error.message);
+                }
+                anyhow::ensure!(
+                    uiaa_session.is_none() || auth_info.completed.len() > completed.len(),
+                    "The homeserver didn't accept the {} step",
+                    stage.unwrap_or_default()
+                );
+                uiaa_session = auth_info.session;
+                completed = auth_info.completed;
+            }
+        }
+    };
+
+    let session = Session {
+        access_token: response
+            .access_token
+            .ok_or_else(|| anyhow::anyhow!("The homeserver didn't log us in"))?,
+        device_id: response
+            .device_id
+            .ok_or_else(|| anyhow::anyhow!("The homeserver didn't create a device"))?,
+        user_id: response.user_id,
+        homeserver: info.homeserver.to_string(),
+    };
+
+    let client = finish_login(paths, info.homeserver, &session).await?;
+    Ok((client, session))
+}
@@ -1,13 +1,38 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use futures::executor::block_on;
 use matrix_sdk::{
-    events::{room::message::MessageEventContent, MessageEvent},
-    identifiers::{RoomAliasId, RoomId, UserId},
-    Client, JoinedRoom,
+    events::{
+        reaction::{ReactionEventContent, Relation},
+        room::power_levels::PowerLevelsEventContent,
+        AnyMessageEventContent, AnyRoomEvent, EventType,
+    },
+    identifiers::{EventId, RoomAliasId, RoomId, UserId},
+    Client, JoinedRoom, Session,
 };
 
-use super::message::MessageBuffer;
+use super::message::Timeline;
+
+use matrix_sdk::api::r0::context::get_context::Request as ContextRequest;
+use matrix_sdk::api::r0::redact::redact_event::Request as RedactRequest;
+use matrix_sdk::api::r0::room::get_room_event::Request as EventRequest;
+use matrix_sdk::api::r0::state::get_state_events_for_key::Request as StateRequest;
+
+/// Power level the spec defaults to for redacting other people's messages
+const DEFAULT_REDACT_LEVEL: i64 = 50;
+/// How long the SDK waits for its requests, used for the ones it can't make for us
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a member of a room is shown
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub name: String,
+    /// mxc url of their avatar
+    pub avatar: Option<String>,
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct RoomEntry {
@@ -23,23 +48,201 @@ pub struct RoomEntry {
     pub avatar: Option<String>,
     /// Person we're in a direct message with
     pub direct: Option<UserId>,
-    /// Cache of messages
-    pub messages: MessageBuffer,
-
-    pub message_list: Vec<MessageEvent<MessageEventContent>>,
+    /// Messages of the room, the view renders only from this
+    pub timeline: Timeline,
+    /// Whether the full member list was fetched, syncs only lazy load members
+    pub members_loaded: bool,
+    /// Power level needed to remove other people's messages, from the last power levels event
+    /// we've seen
+    pub redact_level: Option<i64>,
+    /// Our own power level in the room
+    pub own_power: i64,
+    /// Users that messages link to, looked up in the background since the view can't wait for
+    /// the store. `None` if they aren't in the room
+    pub members: HashMap<UserId, Option<Member>>,
+    /// Users we're looking up
+    pub resolving: HashSet<UserId>,
 }
 
 impl RoomEntry {
-    pub async fn from_sdk(room: &matrix_sdk::JoinedRoom) -> Self {
+    /// Builds the entry of a room `user` joined from what the store has, so it doesn't wait for
+    /// the server. The power levels come later from a sync or `power_levels`
+    pub async fn from_sdk(room: &matrix_sdk::JoinedRoom, user: &UserId) -> Self {
+        let own_power = match room.get_member(user).await {
+            Ok(Some(member)) => member.power_level(),
+            _ => 0,
+        };
         Self {
             direct: room.direct_target(),
             name: room.display_name().await.unwrap_or_default(),
             topic: room.topic().unwrap_or_default(),
             alias: room.canonical_alias(),
             avatar: room.avatar_url(),
+            own_power,
             ..Default::default()
         }
     }
+
+    /// Takes the levels `user` needs from a power levels event
+    pub fn set_power_levels(&mut self, levels: &PowerLevelsEventContent, user: &UserId) {
+        self.redact_level = Some(levels.redact.into());
+        self.own_power = levels
+            .users
+            .get(user)
+            .copied()
+            .unwrap_or(levels.users_default)
+            .into();
+    }
+
+    pub fn redact_level(&self) -> i64 {
+        self.redact_level.unwrap_or(DEFAULT_REDACT_LEVEL)
+    }
+}
+
+/// Removes a message from a room
+pub async fn redact(
+    client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    reason: Option<&str>,
+) -> Result<(), matrix_sdk::Error> {
+    // Only needs to be unique among the requests of this device
+    let txn_id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string();
+    let mut request = RedactRequest::new(room_id, event_id, &txn_id);
+    request.reason = reason;
+    client.send(request, None).await?;
+    Ok(())
+}
+
+/// Fetches a single event of a room from the server
+pub async fn fetch_event(
+    client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Result<AnyRoomEvent, anyhow::Error> {
+    let response = client
+        .send(EventRequest::new(room_id, event_id), None)
+        .await?;
+    Ok(response.event.deserialize()?)
+}
+
+/// Replies in the thread of `root`, newest first, with the token to load older ones from.
+///
+/// The SDK has no request for the relations endpoint, so this asks the homeserver directly with
+/// the same TLS, proxy and timeout settings the SDK uses.
+pub async fn thread_replies(
+    client: &Client,
+    session: &Session,
+    room_id: &RoomId,
+    root: &EventId,
+    from: Option<&str>,
+    limit: u32,
+) -> Result<(Vec<AnyRoomEvent>, Option<String>), anyhow::Error> {
+    let mut url = client.homeserver().clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid homeserver url {}", client.homeserver()))?
+        .pop_if_empty()
+        .extend(&["_matrix", "client", "unstable", "rooms"])
+        .push(room_id.as_str())
+        .push("relations")
+        .push(root.as_str())
+        .push("m.thread");
+    url.query_pairs_mut()
+        .append_pair("limit", &limit.to_string());
+    if let Some(from) = from {
+        url.query_pairs_mut().append_pair("from", from);
+    }
+    let response = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?
+        .get(url)
+        .bearer_auth(&session.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let response: serde_json::Value = serde_json::from_str(&response)?;
+    let events = response
+        .get("chunk")
+        .and_then(|chunk| chunk.as_array())
+        .map(|chunk| {
+            chunk
+                .iter()
+                // Skip what we can't read instead of losing the whole page
+                .filter_map(|event| match serde_json::from_value(event.clone()) {
+                    Ok(event) => Some(event),
+                    Err(e) => {
+                        tracing::warn!("Skipping a reply in the thread of {}: {}", root, e);
+                        None
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let next_batch = response
+        .get("next_batch")
+        .and_then(|token| token.as_str())
+        .map(String::from);
+    Ok((events, next_batch))
+}
+
+/// Looks up how a user is shown in a room, `None` if they aren't a member
+pub async fn resolve_member(client: &Client, room_id: &RoomId, user_id: &UserId) -> Option<Member> {
+    let joined = client.get_joined_room(room_id)?;
+    match joined.get_member(user_id).await {
+        Ok(member) => member.map(|member| Member {
+            name: member.name().to_owned(),
+            avatar: member.avatar_url().map(String::from),
+        }),
+        Err(e) => {
+            tracing::warn!("Looking up {} failed: {}", user_id, e);
+            None
+        }
+    }
+}
+
+/// Gets the current power levels of a room from the server
+pub async fn power_levels(
+    client: &Client,
+    room_id: &RoomId,
+) -> Result<PowerLevelsEventContent, anyhow::Error> {
+    let request = StateRequest::new(room_id, EventType::RoomPowerLevels, "");
+    let response = client.send(request, None).await?;
+    Ok(serde_json::from_str(response.content.json().get())?)
+}
+
+/// Gets a token to paginate back from an event with
+pub async fn token_before(
+    client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Result<String, anyhow::Error> {
+    let mut request = ContextRequest::new(room_id, event_id);
+    // We only need the token, not the events around it
+    request.limit = 0u32.into();
+    let response = client.send(request, None).await?;
+    response
+        .start
+        .ok_or_else(|| anyhow::anyhow!("The server sent no token for {}", event_id))
+}
+
+/// Reacts to a message with `key`
+pub async fn react(
+    client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    key: &str,
+) -> Result<(), matrix_sdk::Error> {
+    let content = ReactionEventContent::new(Relation::new(event_id.clone(), key.to_owned()));
+    client
+        .room_send(room_id, AnyMessageEventContent::Reaction(content), None)
+        .await?;
+    Ok(())
 }
 
 pub fn partition_rooms<'a>(
@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use url::Url;
+
+/// How long we wait for the user to finish logging in in the browser
+const SSO_TIMEOUT: Duration = Duration::from_secs(300);
+
+const RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+    Content-Type: text/html; charset=utf-8\r\n\
+    Connection: close\r\n\r\n\
+    <html><body>You can close this window and return to Elementary.</body></html>";
+
+/// Opens the homeserver's SSO page in the browser and waits for it to redirect back to a
+/// loopback listener with a `loginToken`
+pub async fn sso_login_token(homeserver: &Url) -> Result<String, anyhow::Error> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let redirect_url = format!("http://{}/", listener.local_addr()?);
+
+    let mut sso_url = homeserver.clone();
+    sso_url
+        .path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid homeserver url {}", homeserver))?
+        .pop_if_empty()
+        .extend(&["_matrix", "client", "r0", "login", "sso", "redirect"]);
+    sso_url
+        .query_pairs_mut()
+        .append_pair("redirectUrl", &redirect_url);
+    open::that(sso_url.as_str()).context("Failed to open the browser")?;
+
+    tokio::time::timeout(SSO_TIMEOUT, wait_for_token(&listener, &redirect_url))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out waiting for the single sign-on to finish"))?
+}
+
+async fn wait_for_token(
+    listener: &TcpListener,
+    redirect_url: &str,
+) -> Result<String, anyhow::Error> {
+    let base = Url::parse(redirect_url)?;
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buffer = vec![0; 8192];
+        let read = stream.read(&mut buffer).await?;
+        let request = String::from_utf8_lossy(&buffer[..read]);
+
+        // Only the request line matters: `GET /?loginToken=... HTTP/1.1`
+        let path = request
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .unwrap_or_default();
+        let token = base.join(path).ok().and_then(|url| {
+            url.query_pairs()
+                .find(|(key, _)| key == "loginToken")
+                .map(|(_, value)| value.into_owned())
+        });
+
+        // Browsers also ask for things like favicons, ignore those
+        if let Some(token) = token {
+            stream.write_all(RESPONSE.as_bytes()).await.ok();
+            return Ok(token);
+        }
+        stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n")
+            .await
+            .ok();
+    }
+}
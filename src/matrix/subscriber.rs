@@ -1,86 +1,336 @@
 use async_stream::stream;
-use std::time::Duration;
+use std::{collections::HashMap, convert::TryFrom, time::Duration};
 
 use matrix_sdk::{
-    events::{AnyRoomEvent, AnySyncRoomEvent, AnyToDeviceEvent},
-    LoopCtrl, SyncSettings,
+    deserialized_responses::SyncResponse,
+    events::{
+        presence::PresenceEvent, receipt::ReceiptEventContent, AnyBasicEvent, AnyRoomEvent,
+        AnyStrippedStateEvent, AnySyncEphemeralRoomEvent, AnySyncMessageEvent, AnySyncRoomEvent,
+        AnyToDeviceEvent,
+    },
+    identifiers::{EventId, RoomId, UserId},
+    HttpError,
 };
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::paths::Paths;
+
+use super::{
+    filter::{sync_filter, sync_settings},
+    login::is_unknown_token,
+    message::thread_root,
+};
+
+use matrix_sdk::api::r0::message::get_message_events::Request as MessageRequest;
 
 pub struct MatrixSync {
+    /// Where the sync filter id of the account is stored
+    paths: Paths,
     client: matrix_sdk::Client,
-    join: Option<tokio::task::JoinHandle<()>>,
+    /// Account the client is logged in as, so every account gets its own subscription
+    user_id: UserId,
+    /// How long the server may hold a sync request open
+    timeout: Duration,
 }
 
 #[derive(Debug, Clone)]
 pub enum MatrixEvents {
+    /// State of joined rooms and the timeline of rooms we left
     Room(AnyRoomEvent),
+    /// The timeline of a joined room from one sync
+    Timeline {
+        room_id: RoomId,
+        events: Vec<AnyRoomEvent>,
+        /// Whether the server left out events since the previous sync
+        limited: bool,
+        /// Token to paginate back from the first event
+        prev_batch: Option<String>,
+        /// Token to paginate forward from the last event
+        next_batch: String,
+        /// Events sent in a thread, with the root of their thread
+        thread_roots: Vec<(EventId, EventId)>,
+    },
     ToDevice(AnyToDeviceEvent),
+    /// Users currently typing in a room
+    Typing(RoomId, Vec<UserId>),
+    /// Read receipts of a room
+    Receipt(RoomId, ReceiptEventContent),
+    Presence(PresenceEvent),
+    /// Account data that isn't tied to a room
+    AccountData(AnyBasicEvent),
+    RoomAccountData(RoomId, AnyBasicEvent),
+    /// We were invited to a room, with the state the inviter shared
+    Invited(RoomId, Vec<AnyStrippedStateEvent>),
+    /// We left or were removed from a room
+    Left(RoomId),
+    /// Unread notification counts of a joined room
+    UnreadCounts {
+        room_id: RoomId,
+        notifications: u64,
+        highlights: u64,
+    },
+    /// The server no longer accepts our access token
+    LoggedOut,
+    /// The connection to the server changed
+    Status(SyncStatus),
+}
+
+/// State of the connection to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncStatus {
+    /// Waiting for the first sync response
+    Connecting,
+    /// The last sync succeeded
+    Syncing,
+    /// The server couldn't be reached, we try again after `retry_in`
+    Offline { retry_in: Duration },
+    /// The server answered with an error, we try again after `retry_in`
+    Error { message: String, retry_in: Duration },
+}
+
+impl Default for SyncStatus {
+    fn default() -> Self {
+        SyncStatus::Connecting
+    }
+}
+
+/// Delay before the first retry of a failed sync
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest we wait between two sync attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(64);
+
+/// Stops the sync task once the subscription is dropped
+struct SyncTask(tokio::task::JoinHandle<()>);
+
+impl Drop for SyncTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Adds the room id back to a timeline event
+fn full_event(id: RoomId, event: AnySyncRoomEvent) -> AnyRoomEvent {
+    match event {
+        AnySyncRoomEvent::Message(e) => AnyRoomEvent::Message(e.into_full_event(id)),
+        AnySyncRoomEvent::State(e) => AnyRoomEvent::State(e.into_full_event(id)),
+        AnySyncRoomEvent::RedactedMessage(e) => {
+            AnyRoomEvent::RedactedMessage(e.into_full_event(id))
+        }
+        AnySyncRoomEvent::RedactedState(e) => AnyRoomEvent::RedactedState(e.into_full_event(id)),
+    }
+}
+
+/// Syncs once with the filter of the account, uploading the filter first if needed
+async fn sync_once(
+    paths: &Paths,
+    client: &matrix_sdk::Client,
+    user_id: &UserId,
+    filter_id: &mut Option<String>,
+    timeout: Duration,
+) -> Result<SyncResponse, matrix_sdk::Error> {
+    let filter = match filter_id.clone() {
+        Some(filter) => filter,
+        None => {
+            let filter = sync_filter(paths, client, user_id).await?;
+            *filter_id = Some(filter.clone());
+            filter
+        }
+    };
+    // Without a token we're doing an initial sync
+    let mut settings = sync_settings(&filter).timeout(timeout);
+    if let Some(token) = client.sync_token().await {
+        settings = settings.token(token);
+    }
+    client.sync_once(settings).await
+}
+
+/// Roots of the threads that the new messages of a sync were sent in, by room.
+///
+/// The SDK drops relations it doesn't know, so this gets the timeline of every room with new
+/// messages again as JSON, with one request for each room instead of one for each message
+async fn thread_roots(
+    client: &matrix_sdk::Client,
+    response: &SyncResponse,
+) -> HashMap<RoomId, Vec<(EventId, EventId)>> {
+    let mut roots = HashMap::new();
+    for (id, room) in &response.rooms.join {
+        let has_messages = room.timeline.events.iter().any(|event| {
+            matches!(
+                event,
+                AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(_))
+            )
+        });
+        if !has_messages {
+            continue;
+        }
+        let mut request = MessageRequest::backward(id, &response.next_batch);
+        request.limit = (room.timeline.events.len() as u32).into();
+        let chunk = match client.send(request, None).await {
+            Ok(page) => page.chunk,
+            Err(e) => {
+                tracing::warn!("Looking for threads in {} failed: {}", id, e);
+                continue;
+            }
+        };
+        let threads: Vec<(EventId, EventId)> = chunk
+            .iter()
+            .filter_map(|event| {
+                let json: serde_json::Value = serde_json::from_str(event.json().get()).ok()?;
+                let root = thread_root(&json)?;
+                let id = EventId::try_from(json.get("event_id")?.as_str()?).ok()?;
+                Some((id, root))
+            })
+            .collect();
+        if !threads.is_empty() {
+            roots.insert(id.clone(), threads);
+        }
+    }
+    roots
+}
+
+/// Sends the events of a sync response to the subscription
+fn forward_response(
+    sender: &UnboundedSender<MatrixEvents>,
+    response: SyncResponse,
+    mut thread_roots: HashMap<RoomId, Vec<(EventId, EventId)>>,
+) {
+    let next_batch = response.next_batch;
+    for (id, room) in response.rooms.join {
+        for event in room.state.events {
+            let id = id.clone();
+            sender
+                .send(MatrixEvents::Room(AnyRoomEvent::State(
+                    event.into_full_event(id),
+                )))
+                .ok();
+        }
+        sender
+            .send(MatrixEvents::Timeline {
+                room_id: id.clone(),
+                events: room
+                    .timeline
+                    .events
+                    .into_iter()
+                    .map(|event| full_event(id.clone(), event))
+                    .collect(),
+                limited: room.timeline.limited,
+                prev_batch: room.timeline.prev_batch,
+                next_batch: next_batch.clone(),
+                thread_roots: thread_roots.remove(&id).unwrap_or_default(),
+            })
+            .ok();
+        for event in room.ephemeral.events {
+            let event = match event {
+                AnySyncEphemeralRoomEvent::Typing(e) => {
+                    MatrixEvents::Typing(id.clone(), e.content.user_ids)
+                }
+                AnySyncEphemeralRoomEvent::Receipt(e) => {
+                    MatrixEvents::Receipt(id.clone(), e.content)
+                }
+                _ => continue,
+            };
+            sender.send(event).ok();
+        }
+        for event in room.account_data.events {
+            sender
+                .send(MatrixEvents::RoomAccountData(id.clone(), event))
+                .ok();
+        }
+        sender
+            .send(MatrixEvents::UnreadCounts {
+                room_id: id,
+                notifications: room.unread_notifications.notification_count,
+                highlights: room.unread_notifications.highlight_count,
+            })
+            .ok();
+    }
+    for (id, room) in response.rooms.invite {
+        sender
+            .send(MatrixEvents::Invited(id, room.invite_state.events))
+            .ok();
+    }
+    for (id, room) in response.rooms.leave {
+        // The timeline holds the event that made us leave
+        for event in room.timeline.events {
+            sender
+                .send(MatrixEvents::Room(full_event(id.clone(), event)))
+                .ok();
+        }
+        sender.send(MatrixEvents::Left(id)).ok();
+    }
+    for event in response.presence.events {
+        sender.send(MatrixEvents::Presence(event)).ok();
+    }
+    for event in response.account_data.events {
+        sender.send(MatrixEvents::AccountData(event)).ok();
+    }
+    for event in response.to_device.events {
+        sender.send(MatrixEvents::ToDevice(event)).ok();
+    }
 }
 
 impl<H, I> iced_native::subscription::Recipe<H, I> for MatrixSync
 where
     H: std::hash::Hasher,
 {
-    type Output = MatrixEvents;
+    type Output = (UserId, MatrixEvents);
     fn hash(&self, state: &mut H) {
         use std::hash::Hash;
         std::any::TypeId::of::<Self>().hash(state);
-        //self.id.hash(state);
+        self.user_id.hash(state);
     }
     fn stream(
-        mut self: Box<Self>,
+        self: Box<Self>,
         _input: iced_futures::BoxStream<I>,
     ) -> iced_futures::BoxStream<Self::Output> {
         let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let paths = self.paths.clone();
         let client = self.client.clone();
+        let user_id = self.user_id.clone();
+        let task_user_id = user_id.clone();
+        let timeout = self.timeout;
         let join = tokio::task::spawn(async move {
-            client
-                .sync_with_callback(
-                    SyncSettings::new()
-                        .token(client.sync_token().await.unwrap())
-                        .timeout(Duration::from_secs(30)),
-                    |response| async {
-                        //sender.send(Event::Token(response.next_batch)).ok();
-                        for (id, room) in response.rooms.join {
-                            for event in room.state.events {
-                                let id = id.clone();
-                                sender
-                                    .send(Self::Output::Room(AnyRoomEvent::State(
-                                        event.into_full_event(id),
-                                    )))
-                                    .ok();
-                            }
-                            for event in room.timeline.events {
-                                let id = id.clone();
-                                let event = match event {
-                                    AnySyncRoomEvent::Message(e) => {
-                                        AnyRoomEvent::Message(e.into_full_event(id))
-                                    }
-                                    AnySyncRoomEvent::State(e) => {
-                                        AnyRoomEvent::State(e.into_full_event(id))
-                                    }
-                                    AnySyncRoomEvent::RedactedMessage(e) => {
-                                        AnyRoomEvent::RedactedMessage(e.into_full_event(id))
-                                    }
-                                    AnySyncRoomEvent::RedactedState(e) => {
-                                        AnyRoomEvent::RedactedState(e.into_full_event(id))
-                                    }
-                                };
-                                sender.send(Self::Output::Room(event)).ok();
-                            }
-                        }
-                        for event in response.to_device.events {
-                            sender.send(Self::Output::ToDevice(event)).ok();
+            let mut status = SyncStatus::Connecting;
+            let mut backoff = MIN_BACKOFF;
+            let mut filter_id = None;
+            sender.send(MatrixEvents::Status(status.clone())).ok();
+            loop {
+                match sync_once(&paths, &client, &task_user_id, &mut filter_id, timeout).await {
+                    Ok(response) => {
+                        backoff = MIN_BACKOFF;
+                        let roots = thread_roots(&client, &response).await;
+                        forward_response(&sender, response, roots);
+                        // Only tell the UI when something changed
+                        if status != SyncStatus::Syncing {
+                            status = SyncStatus::Syncing;
+                            sender.send(MatrixEvents::Status(status.clone())).ok();
                         }
-                        LoopCtrl::Continue
-                    },
-                )
-                .await;
+                    }
+                    Err(e) if is_unknown_token(&e) => {
+                        sender.send(MatrixEvents::LoggedOut).ok();
+                        break;
+                    }
+                    Err(e) => {
+                        status = match e {
+                            matrix_sdk::Error::Http(HttpError::Reqwest(_)) => {
+                                SyncStatus::Offline { retry_in: backoff }
+                            }
+                            e => SyncStatus::Error {
+                                message: e.to_string(),
+                                retry_in: backoff,
+                            },
+                        };
+                        sender.send(MatrixEvents::Status(status.clone())).ok();
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            }
         });
-        self.join = Some(join);
         let stream = stream! {
+            let _task = SyncTask(join);
             while let Some(item) = receiver.recv().await {
-                yield item;
+                yield (user_id.clone(), item);
             }
         };
         Box::pin(stream)
@@ -88,7 +338,17 @@ where
 }
 
 impl MatrixSync {
-    pub fn subscription(client: matrix_sdk::Client) -> iced::Subscription<MatrixEvents> {
-        iced::Subscription::from_recipe(MatrixSync { client, join: None })
+    pub fn subscription(
+        paths: Paths,
+        client: matrix_sdk::Client,
+        user_id: UserId,
+        timeout: Duration,
+    ) -> iced::Subscription<(UserId, MatrixEvents)> {
+        iced::Subscription::from_recipe(MatrixSync {
+            paths,
+            client,
+            user_id,
+            timeout,
+        })
     }
 }
@@ -0,0 +1,269 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use matrix_sdk::identifiers::{DeviceId, UserId};
+
+const APP_NAME: &str = "elementary-desktop";
+/// Files next to the old shared store that aren't part of it, the old session file and the
+/// fallback configuration directory
+const LEGACY_FILES: &[&str] = &["session.toml", APP_NAME];
+
+/// Where the application keeps its files
+#[derive(Debug, Clone)]
+pub struct Paths {
+    /// Database and per device stores
+    data: PathBuf,
+    /// Configuration files
+    config: PathBuf,
+}
+
+impl Default for Paths {
+    /// `$XDG_DATA_HOME/elementary-desktop` and `$XDG_CONFIG_HOME/elementary-desktop`
+    fn default() -> Self {
+        let data = dirs::data_dir().unwrap_or_else(|| PathBuf::from("./data"));
+        let config = dirs::config_dir().unwrap_or_else(|| PathBuf::from("./data/config"));
+        Self {
+            data: data.join(APP_NAME),
+            config: config.join(APP_NAME),
+        }
+    }
+}
+
+impl Paths {
+    /// Builds the paths from the command line arguments.
+    ///
+    /// `--data-dir <dir>` keeps everything inside `dir` and `--profile <name>` keeps a separate
+    /// set of accounts and settings under the name.
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, anyhow::Error> {
+        let mut paths = Self::default();
+        let mut profile = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--data-dir" => {
+                    let dir = PathBuf::from(
+                        args.next()
+                            .ok_or_else(|| anyhow::anyhow!("--data-dir needs a directory"))?,
+                    );
+                    paths = Self {
+                        config: dir.join("config"),
+                        data: dir,
+                    };
+                }
+                "--profile" => {
+                    profile = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow::anyhow!("--profile needs a name"))?,
+                    );
+                }
+                _ => anyhow::bail!("Unknown argument {}", arg),
+            }
+        }
+        if let Some(profile) = profile {
+            anyhow::ensure!(
+                !profile.is_empty() && !profile.contains(std::path::is_separator),
+                "Invalid profile name {}",
+                profile
+            );
+            paths.data.push(&profile);
+            paths.config.push(&profile);
+        }
+        Ok(paths)
+    }
+
+    /// Creates the directories if this is the first run, moving the database of older versions
+    /// into them
+    pub fn create(&self) -> Result<(), io::Error> {
+        std::fs::create_dir_all(&self.data)?;
+        std::fs::create_dir_all(&self.config)?;
+        let database = self.database();
+        if database.exists() {
+            return Ok(());
+        }
+        match legacy_database() {
+            Some(legacy) => {
+                tracing::info!("Moving the database of an older version from {:?}", legacy);
+                if std::fs::rename(&legacy, &database).is_err() {
+                    std::fs::copy(&legacy, &database)?;
+                    std::fs::remove_file(&legacy)?;
+                }
+            }
+            None => tracing::debug!("No database of an older version to move"),
+        }
+        Ok(())
+    }
+
+    /// User configuration file
+    pub fn config_file(&self) -> PathBuf {
+        self.config.join("config.toml")
+    }
+
+    /// SQLite database holding the sessions
+    pub fn database(&self) -> PathBuf {
+        self.data.join("elementary.db")
+    }
+
+    /// Directory holding the state and crypto store of a device
+    pub fn store(&self, user_id: &UserId, device_id: &DeviceId) -> PathBuf {
+        self.data
+            .join("store")
+            .join(user_id.as_str())
+            .join(device_id.as_str())
+    }
+
+    /// Directory holding the state and crypto store every session shared before each device got
+    /// its own, if it is still there
+    fn legacy_store(&self) -> Option<PathBuf> {
+        legacy_data_dirs()
+            .into_iter()
+            .map(|dir| dir.join("config"))
+            .find(|path| {
+                std::fs::read_dir(path)
+                    .map(|entries| {
+                        entries.filter_map(Result::ok).any(|entry| {
+                            !LEGACY_FILES.iter().any(|name| entry.file_name() == *name)
+                        })
+                    })
+                    .unwrap_or(false)
+            })
+    }
+
+    /// Session file older versions kept, if it is still there
+    pub fn legacy_session_file(&self) -> Option<PathBuf> {
+        legacy_data_dirs()
+            .into_iter()
+            .map(|dir| dir.join("config").join("session.toml"))
+            .find(|path| path.is_file())
+    }
+
+    /// Moves the old shared store into the store of a device, returning whether there was one
+    pub fn move_legacy_store(&self, to: &Path) -> Result<bool, io::Error> {
+        match self.legacy_store() {
+            Some(from) => {
+                tracing::info!("Moving the store of an older version from {:?}", from);
+                move_dir(&from, to, LEGACY_FILES).map(|_| true)
+            }
+            None => {
+                tracing::debug!("No store of an older version to move");
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Where older versions may have kept their files.
+///
+/// They used a `data` directory relative to the working directory, which was usually the
+/// checkout they were run from with `cargo run`. If the application is started from somewhere
+/// else now, the directory next to the executable is the other place it may be.
+fn legacy_data_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(dir) = std::env::current_dir() {
+        dirs.push(dir.join("data"));
+    }
+    if let Some(dir) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_owned))
+    {
+        dirs.push(dir.join("data"));
+    }
+    dirs
+}
+
+/// Database older versions kept wherever `DATABASE_URL` pointed to, if it is still there
+fn legacy_database() -> Option<PathBuf> {
+    match std::env::var_os("DATABASE_URL") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => legacy_data_dirs()
+            .into_iter()
+            .map(|dir| dir.join("session.db"))
+            .find(|path| path.is_file()),
+    }
+    .filter(|path| path.is_file())
+}
+
+/// Moves the contents of a directory into `to` apart from the entries named in `skip`, copying
+/// what can't be renamed because it is on another file system
+fn move_dir(from: &Path, to: &Path, skip: &[&str]) -> Result<(), io::Error> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if skip.iter().any(|name| entry.file_name() == *name) {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        if std::fs::rename(entry.path(), &target).is_err() {
+            if entry.file_type()?.is_dir() {
+                move_dir(&entry.path(), &target, &[])?;
+                std::fs::remove_dir(entry.path())?;
+            } else {
+                std::fs::copy(entry.path(), &target)?;
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &[&str]) -> Result<Paths, anyhow::Error> {
+        Paths::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn no_arguments_use_the_default_directories() {
+        let paths = from_args(&[]).unwrap();
+        let default = Paths::default();
+        assert_eq!(paths.data, default.data);
+        assert_eq!(paths.config, default.config);
+        assert!(paths.data.ends_with(APP_NAME));
+        assert!(paths.config.ends_with(APP_NAME));
+    }
+
+    #[test]
+    fn data_dir_keeps_everything_inside() {
+        let paths = from_args(&["--data-dir", "/tmp/elementary"]).unwrap();
+        assert_eq!(paths.data, PathBuf::from("/tmp/elementary"));
+        assert_eq!(paths.config, PathBuf::from("/tmp/elementary/config"));
+        assert_eq!(
+            paths.database(),
+            PathBuf::from("/tmp/elementary/elementary.db")
+        );
+        assert_eq!(
+            paths.config_file(),
+            PathBuf::from("/tmp/elementary/config/config.toml")
+        );
+    }
+
+    #[test]
+    fn profiles_get_their_own_directories() {
+        let default = Paths::default();
+        let paths = from_args(&["--profile", "work"]).unwrap();
+        assert_eq!(paths.data, default.data.join("work"));
+        assert_eq!(paths.config, default.config.join("work"));
+
+        // The order of the arguments doesn't matter
+        for args in &[
+            ["--data-dir", "/tmp/elementary", "--profile", "work"],
+            ["--profile", "work", "--data-dir", "/tmp/elementary"],
+        ] {
+            let paths = from_args(args).unwrap();
+            assert_eq!(paths.data, PathBuf::from("/tmp/elementary/work"));
+            assert_eq!(paths.config, PathBuf::from("/tmp/elementary/config/work"));
+        }
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert!(from_args(&["--data-dir"]).is_err());
+        assert!(from_args(&["--profile"]).is_err());
+        assert!(from_args(&["--profile", ""]).is_err());
+        assert!(from_args(&["--profile", "../work"]).is_err());
+        assert!(from_args(&["--verbose"]).is_err());
+        assert!(from_args(&["/tmp/elementary"]).is_err());
+    }
+}
@@ -0,0 +1,793 @@
+use super::{push_span, safe_link, Block, Span, Style};
+
+/// Tags the spec allows in formatted bodies, other tags are dropped but their text is kept
+const ALLOWED_TAGS: &[&str] = &[
+    "font",
+    "del",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "p",
+    "a",
+    "ul",
+    "ol",
+    "sup",
+    "sub",
+    "li",
+    "b",
+    "i",
+    "u",
+    "strong",
+    "em",
+    "strike",
+    "code",
+    "hr",
+    "br",
+    "div",
+    "table",
+    "thead",
+    "tbody",
+    "tr",
+    "th",
+    "td",
+    "caption",
+    "pre",
+    "span",
+];
+/// Tags dropped together with their content, the reply fallback duplicates what we show anyway
+const DROPPED_TAGS: &[&str] = &["mx-reply", "script", "style", "head"];
+/// Tags that never have content
+const VOID_TAGS: &[&str] = &["br", "hr", "img"];
+
+/// Element tree of an HTML document
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Element {
+        name: String,
+        attrs: Vec<(String, String)>,
+        children: Vec<Node>,
+    },
+}
+
+/// Parses a formatted body into blocks, dropping anything the spec doesn't allow
+pub fn parse(html: &str) -> Vec<Block> {
+    let mut builder = Builder::default();
+    builder.walk(&parse_nodes(html), &Style::default());
+    builder.blocks()
+}
+
+/// Replaces character references with the characters they stand for
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest.find(';').filter(|&end| end <= 10);
+        let c = entity.and_then(|end| match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            name if name.starts_with("#x") || name.starts_with("#X") => {
+                u32::from_str_radix(&name[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+            }
+            name if name.starts_with('#') => name[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        });
+        match (c, entity) {
+            (Some(c), Some(end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Parses the start tag at the beginning of `html`, returning its name, attributes, whether
+/// it closes itself and the rest of the input
+fn parse_start_tag(html: &str) -> (String, Vec<(String, String)>, bool, &str) {
+    let is_name_end = |c: char| c.is_whitespace() || c == '/' || c == '>';
+    let rest = &html[1..];
+    let name_end = rest.find(is_name_end).unwrap_or_else(|| rest.len());
+    let name = rest[..name_end].to_ascii_lowercase();
+    let mut rest = &rest[name_end..];
+    let mut attrs = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return (name, attrs, false, rest);
+        } else if let Some(rest) = rest.strip_prefix('>') {
+            return (name, attrs, false, rest);
+        } else if let Some(rest) = rest.strip_prefix("/>") {
+            return (name, attrs, true, rest);
+        } else if let Some(after) = rest.strip_prefix('/') {
+            rest = after;
+            continue;
+        }
+
+        let key_end = rest
+            .find(|c: char| is_name_end(c) || c == '=')
+            .unwrap_or_else(|| rest.len());
+        let key = rest[..key_end].to_ascii_lowercase();
+        rest = rest[key_end..].trim_start();
+        let mut value = String::new();
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let (raw, remaining) = match after.chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => {
+                    let after = &after[1..];
+                    match after.find(quote) {
+                        Some(end) => (&after[..end], &after[end + 1..]),
+                        None => (after, ""),
+                    }
+                }
+                _ => {
+                    let end = after
+                        .find(|c: char| c.is_whitespace() || c == '>')
+                        .unwrap_or_else(|| after.len());
+                    (&after[..end], &after[end..])
+                }
+            };
+            value = decode_entities(raw);
+            rest = remaining;
+        }
+        attrs.push((key, value));
+    }
+}
+
+/// Builds the element tree, closing elements left open and ignoring stray end tags
+fn parse_nodes(html: &str) -> Vec<Node> {
+    // Open elements with the children they have so far, the first one being the document
+    let mut stack: Vec<(String, Vec<(String, String)>, Vec<Node>)> =
+        vec![(String::new(), Vec::new(), Vec::new())];
+    let close = |stack: &mut Vec<(String, Vec<(String, String)>, Vec<Node>)>| {
+        if let Some((name, attrs, children)) = stack.pop() {
+            if let Some(parent) = stack.last_mut() {
+                parent.2.push(Node::Element {
+                    name,
+                    attrs,
+                    children,
+                });
+            }
+        }
+    };
+
+    let mut rest = html;
+    while !rest.is_empty() {
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = match comment.find("-->") {
+                Some(end) => &comment[end + 3..],
+                None => "",
+            };
+        } else if let Some(tag) = rest.strip_prefix("</") {
+            let end = tag.find('>').unwrap_or_else(|| tag.len());
+            let name = tag[..end].trim().to_ascii_lowercase();
+            rest = tag.get(end + 1..).unwrap_or("");
+            if let Some(open) = stack.iter().skip(1).rposition(|(open, ..)| *open == name) {
+                while stack.len() > open + 1 {
+                    close(&mut stack);
+                }
+            }
+        } else if rest.starts_with('<')
+            && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '!')
+        {
+            if rest[1..].starts_with('!') {
+                // Doctype and other declarations
+                let end = rest.find('>').unwrap_or_else(|| rest.len() - 1);
+                rest = &rest[end + 1..];
+                continue;
+            }
+            let (name, attrs, self_closing, remaining) = parse_start_tag(rest);
+            rest = remaining;
+            if self_closing || VOID_TAGS.contains(&name.as_str()) {
+                if let Some(parent) = stack.last_mut() {
+                    parent.2.push(Node::Element {
+                        name,
+                        attrs,
+                        children: Vec::new(),
+                    });
+                }
+            } else {
+                stack.push((name, attrs, Vec::new()));
+            }
+        } else {
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| c == '<')
+                .map_or(rest.len(), |(end, _)| end);
+            if let Some(parent) = stack.last_mut() {
+                parent.2.push(Node::Text(decode_entities(&rest[..end])));
+            }
+            rest = &rest[end..];
+        }
+    }
+    while stack.len() > 1 {
+        close(&mut stack);
+    }
+    stack
+        .pop()
+        .map(|(_, _, children)| children)
+        .unwrap_or_default()
+}
+
+/// Parses a `#rrggbb` color
+fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.trim().strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+fn attr<'a>(attrs: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    attrs
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Text of the nodes as is, for preformatted text
+fn text_content(nodes: &[Node], text: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(t) => text.push_str(t),
+            Node::Element { name, .. } if name == "br" => text.push('\n'),
+            Node::Element { name, .. } if DROPPED_TAGS.contains(&name.as_str()) => {}
+            Node::Element { children, .. } => text_content(children, text),
+        }
+    }
+}
+
+/// Text of the nodes with their formatting, with blocks inside joined into a single line
+fn inline_spans(nodes: &[Node], style: &Style) -> Vec<Span> {
+    let mut builder = Builder::default();
+    builder.walk(nodes, style);
+    let mut spans = Vec::new();
+    for block in builder.blocks() {
+        if let Block::Paragraph(paragraph) | Block::Heading(_, paragraph) = block {
+            if !spans.is_empty() {
+                push_span(&mut spans, " ", &Style::default());
+            }
+            for span in paragraph {
+                push_span(&mut spans, &span.text, &span.style);
+            }
+        }
+    }
+    spans
+}
+
+/// Collects the rows of a table, looking into its sections
+fn table_rows(nodes: &[Node], style: &Style, rows: &mut Vec<Vec<Vec<Span>>>, header: &mut bool) {
+    for node in nodes {
+        match node {
+            Node::Element { name, children, .. } if name == "tr" => {
+                let mut row = Vec::new();
+                for cell in children {
+                    match cell {
+                        Node::Element { name, children, .. } if name == "th" || name == "td" => {
+                            let mut style = style.clone();
+                            if name == "th" {
+                                *header |= rows.is_empty();
+                                style.bold = true;
+                            }
+                            row.push(inline_spans(children, &style));
+                        }
+                        _ => {}
+                    }
+                }
+                rows.push(row);
+            }
+            Node::Element { name, children, .. }
+                if name == "thead" || name == "tbody" || name == "tfoot" =>
+            {
+                table_rows(children, style, rows, header)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Turns the element tree into blocks
+#[derive(Default)]
+struct Builder {
+    blocks: Vec<Block>,
+    /// The paragraph being built
+    spans: Vec<Span>,
+}
+
+impl Builder {
+    /// Ends the current paragraph
+    fn flush(&mut self) {
+        let mut spans = std::mem::take(&mut self.spans);
+        if let Some(last) = spans.last_mut() {
+            last.text.truncate(last.text.trim_end().len());
+        }
+        spans.retain(|span| !span.text.is_empty());
+        if !spans.is_empty() {
+            self.blocks.push(Block::Paragraph(spans));
+        }
+    }
+
+    fn blocks(mut self) -> Vec<Block> {
+        self.flush();
+        self.blocks
+    }
+
+    fn text(&mut self, text: &str, style: &Style) {
+        // Runs of whitespace show as a single space, none at the start of a paragraph
+        let mut collapsed = String::with_capacity(text.len());
+        let mut space = self
+            .spans
+            .last()
+            .map_or(true, |span| span.text.ends_with(' '));
+        for c in text.chars() {
+            if c.is_whitespace() && c != '\u{a0}' {
+                if !space {
+                    collapsed.push(' ');
+                    space = true;
+                }
+            } else {
+                collapsed.push(c);
+                space = false;
+            }
+        }
+        push_span(&mut self.spans, &collapsed, style);
+    }
+
+    fn walk(&mut self, nodes: &[Node], style: &Style) {
+        for node in nodes {
+            self.node(node, style);
+        }
+    }
+
+    fn node(&mut self, node: &Node, style: &Style) {
+        let (name, attrs, children) = match node {
+            Node::Text(text) => return self.text(text, style),
+            Node::Element {
+                name,
+                attrs,
+                children,
+            } => (name.as_str(), attrs.as_slice(), children.as_slice()),
+        };
+        if DROPPED_TAGS.contains(&name) {
+            return;
+        }
+        if name == "img" {
+            // We don't load inline images, their description stands in for them
+            if let Some(alt) = attr(attrs, "alt").filter(|alt| !alt.trim().is_empty()) {
+                self.text(&format!("[{}]", alt.trim()), style);
+            }
+            return;
+        }
+        if !ALLOWED_TAGS.contains(&name) {
+            return self.walk(children, style);
+        }
+
+        let mut style = style.clone();
+        match name {
+            "br" => self.flush(),
+            "hr" => {
+                self.flush();
+                self.blocks.push(Block::Rule);
+            }
+            "p" | "div" | "li" | "caption" => {
+                self.flush();
+                self.walk(children, &style);
+                self.flush();
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.flush();
+                let level = name[1..].parse().unwrap_or(1);
+                let spans = inline_spans(children, &style);
+                if !spans.is_empty() {
+                    self.blocks.push(Block::Heading(level, spans));
+                }
+            }
+            "blockquote" => {
+                self.flush();
+                let mut quote = Builder::default();
+                quote.walk(children, &style);
+                self.blocks.push(Block::Quote(quote.blocks()));
+            }
+            "ul" | "ol" => {
+                self.flush();
+                let items = children
+                    .iter()
+                    .filter_map(|child| match child {
+                        Node::Element { name, children, .. } if name == "li" => {
+                            let mut item = Builder::default();
+                            item.walk(children, &style);
+                            Some(item.blocks())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                let start = match name {
+                    "ol" => Some(
+                        attr(attrs, "start")
+                            .and_then(|start| start.parse().ok())
+                            .unwrap_or(1),
+                    ),
+                    _ => None,
+                };
+                self.blocks.push(Block::List { start, items });
+            }
+            "pre" => {
+                self.flush();
+                let language = children.iter().find_map(|child| match child {
+                    Node::Element { name, attrs, .. } if name == "code" => attr(attrs, "class")
+                        .and_then(|class| {
+                            class
+                                .split_whitespace()
+                                .find_map(|class| class.strip_prefix("language-"))
+                        })
+                        .map(ToOwned::to_owned),
+                    _ => None,
+                });
+                let mut code = String::new();
+                text_content(children, &mut code);
+                self.blocks.push(Block::Code {
+                    language,
+                    code: code.trim_end_matches('\n').to_owned(),
+                });
+            }
+            "table" => {
+                self.flush();
+                let mut rows = Vec::new();
+                let mut header = false;
+                table_rows(children, &style, &mut rows, &mut header);
+                self.blocks.push(Block::Table { header, rows });
+            }
+            _ => {
+                match name {
+                    "b" | "strong" => style.bold = true,
+                    "i" | "em" => style.italic = true,
+                    "u" => style.underline = true,
+                    "del" | "strike" => style.strikethrough = true,
+                    "code" => style.code = true,
+                    "a" => {
+                        style.link = attr(attrs, "href")
+                            .filter(|href| safe_link(href))
+                            .map(ToOwned::to_owned)
+                    }
+                    "font" | "span" => {
+                        let color = attr(attrs, "data-mx-color").or_else(|| match name {
+                            "font" => attr(attrs, "color"),
+                            _ => None,
+                        });
+                        if let Some(color) = color.and_then(parse_color) {
+                            style.color = Some(color);
+                        }
+                        if attr(attrs, "data-mx-spoiler").is_some() {
+                            style.spoiler = true;
+                        }
+                    }
+                    _ => {}
+                }
+                self.walk(children, &style);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(text: &str, style: Style) -> Span {
+        Span {
+            text: text.to_owned(),
+            style,
+        }
+    }
+
+    fn paragraph(text: &str) -> Block {
+        Block::Paragraph(vec![span(text, Style::default())])
+    }
+
+    fn bold() -> Style {
+        Style {
+            bold: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn inline_tags_set_their_style() {
+        let italic = Style {
+            italic: true,
+            ..Default::default()
+        };
+        let underline = Style {
+            underline: true,
+            ..Default::default()
+        };
+        let strikethrough = Style {
+            strikethrough: true,
+            ..Default::default()
+        };
+        let code = Style {
+            code: true,
+            ..Default::default()
+        };
+        let cases = vec![
+            ("b", bold()),
+            ("strong", bold()),
+            ("i", italic.clone()),
+            ("em", italic),
+            ("u", underline),
+            ("del", strikethrough.clone()),
+            ("strike", strikethrough),
+            ("code", code),
+            ("sup", Style::default()),
+            ("sub", Style::default()),
+            ("span", Style::default()),
+            ("font", Style::default()),
+        ];
+        for (tag, style) in cases {
+            assert_eq!(
+                parse(&format!("<{0}>text</{0}>", tag)),
+                vec![Block::Paragraph(vec![span("text", style)])],
+                "<{}>",
+                tag
+            );
+        }
+    }
+
+    #[test]
+    fn block_tags_make_their_blocks() {
+        for level in 1..=6 {
+            assert_eq!(
+                parse(&format!("<h{0}>title</h{0}>", level)),
+                vec![Block::Heading(level, vec![span("title", Style::default())])]
+            );
+        }
+        assert_eq!(
+            parse("<p>one</p><div>two</div>three"),
+            vec![paragraph("one"), paragraph("two"), paragraph("three")]
+        );
+        assert_eq!(
+            parse("one<br>two<hr/>three"),
+            vec![
+                paragraph("one"),
+                paragraph("two"),
+                Block::Rule,
+                paragraph("three")
+            ]
+        );
+    }
+
+    #[test]
+    fn whitespace_collapses() {
+        assert_eq!(parse("<p>  one \n  two  </p>"), vec![paragraph("one two")]);
+    }
+
+    #[test]
+    fn reply_fallback_and_scripts_are_dropped_with_their_content() {
+        assert_eq!(
+            parse("<mx-reply><blockquote>quoted</blockquote></mx-reply>reply"),
+            vec![paragraph("reply")]
+        );
+        assert_eq!(
+            parse("<script>alert(1)</script>text"),
+            vec![paragraph("text")]
+        );
+        assert_eq!(
+            parse("<style>p { color: red }</style>text"),
+            vec![paragraph("text")]
+        );
+    }
+
+    #[test]
+    fn unknown_tags_keep_their_text() {
+        assert_eq!(
+            parse("<marquee>moving</marquee> <img alt=\"cat\">"),
+            vec![paragraph("moving [cat]")]
+        );
+    }
+
+    #[test]
+    fn only_safe_links_are_kept() {
+        let link = |href: &str| Style {
+            link: Some(href.to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("<a href=\"https://example.org/?a=1&amp;b=2\">site</a>"),
+            vec![Block::Paragraph(vec![span(
+                "site",
+                link("https://example.org/?a=1&b=2")
+            )])]
+        );
+        assert_eq!(
+            parse("<a href='mailto:alice@example.org'>mail</a>"),
+            vec![Block::Paragraph(vec![span(
+                "mail",
+                link("mailto:alice@example.org")
+            )])]
+        );
+        for href in &[
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            "data:text/html,x",
+            "/relative",
+        ] {
+            assert_eq!(
+                parse(&format!("<a href=\"{}\">text</a>", href)),
+                vec![paragraph("text")],
+                "{}",
+                href
+            );
+        }
+    }
+
+    #[test]
+    fn colors_and_spoilers() {
+        let color = |rgb: [u8; 3]| Style {
+            color: Some(rgb),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("<font data-mx-color=\"#ff0000\">red</font>"),
+            vec![Block::Paragraph(vec![span("red", color([255, 0, 0]))])]
+        );
+        assert_eq!(
+            parse("<span data-mx-color=\"#00FF80\">green</span>"),
+            vec![Block::Paragraph(vec![span("green", color([0, 255, 128]))])]
+        );
+        assert_eq!(
+            parse("<font color=\"#0000ff\">blue</font>"),
+            vec![Block::Paragraph(vec![span("blue", color([0, 0, 255]))])]
+        );
+        // Only fonts have a plain color attribute, and colors need all six digits
+        assert_eq!(
+            parse("<span color=\"#0000ff\">a</span><font data-mx-color=\"#fff\">b</font>"),
+            vec![paragraph("ab")]
+        );
+
+        let spoiler = Style {
+            spoiler: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("<span data-mx-spoiler>secret</span>"),
+            vec![Block::Paragraph(vec![span("secret", spoiler.clone())])]
+        );
+        assert_eq!(
+            parse("<span data-mx-spoiler=\"plot\">secret</span>"),
+            vec![Block::Paragraph(vec![span("secret", spoiler)])]
+        );
+    }
+
+    #[test]
+    fn unclosed_and_stray_tags() {
+        assert_eq!(
+            parse("<b>bold"),
+            vec![Block::Paragraph(vec![span("bold", bold())])]
+        );
+        assert_eq!(parse("one</i>two</p>"), vec![paragraph("onetwo")]);
+        let both = Style {
+            bold: true,
+            italic: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("<b>a<i>b</b>c</i>"),
+            vec![Block::Paragraph(vec![
+                span("a", bold()),
+                span("b", both),
+                span("c", Style::default())
+            ])]
+        );
+    }
+
+    #[test]
+    fn entities_are_decoded() {
+        assert_eq!(
+            parse("&lt;b&gt; &amp; &quot;&#65;&#x42;&apos;"),
+            vec![paragraph("<b> & \"AB'")]
+        );
+        assert_eq!(parse("a&nbsp;b"), vec![paragraph("a\u{a0}b")]);
+        assert_eq!(parse("&unknown; &amp"), vec![paragraph("&unknown; &amp")]);
+    }
+
+    #[test]
+    fn code_blocks_keep_their_language_and_whitespace() {
+        assert_eq!(
+            parse(
+                "<pre><code class=\"language-rust\">fn main() {\n    1 &lt; 2;\n}\n</code></pre>"
+            ),
+            vec![Block::Code {
+                language: Some(String::from("rust")),
+                code: String::from("fn main() {\n    1 < 2;\n}"),
+            }]
+        );
+        assert_eq!(
+            parse("<pre><code>a  b</code></pre>"),
+            vec![Block::Code {
+                language: None,
+                code: String::from("a  b"),
+            }]
+        );
+    }
+
+    #[test]
+    fn nested_lists_and_quotes() {
+        assert_eq!(
+            parse("<ul><li>one<ol start=\"3\"><li>two</li></ol></li><li>three</li></ul>"),
+            vec![Block::List {
+                start: None,
+                items: vec![
+                    vec![
+                        paragraph("one"),
+                        Block::List {
+                            start: Some(3),
+                            items: vec![vec![paragraph("two")]],
+                        },
+                    ],
+                    vec![paragraph("three")],
+                ],
+            }]
+        );
+        assert_eq!(
+            parse("<ol><li>first</li></ol>"),
+            vec![Block::List {
+                start: Some(1),
+                items: vec![vec![paragraph("first")]],
+            }]
+        );
+        assert_eq!(
+            parse("<blockquote><p>outer</p><blockquote>inner</blockquote></blockquote>"),
+            vec![Block::Quote(vec![
+                paragraph("outer"),
+                Block::Quote(vec![paragraph("inner")]),
+            ])]
+        );
+    }
+
+    #[test]
+    fn tables_with_headers() {
+        let code = Style {
+            code: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse(
+                "<table><thead><tr><th>Name</th><th>Value</th></tr></thead>\
+                 <tbody><tr><td>a</td><td><code>1</code></td></tr></tbody></table>"
+            ),
+            vec![Block::Table {
+                header: true,
+                rows: vec![
+                    vec![vec![span("Name", bold())], vec![span("Value", bold())]],
+                    vec![vec![span("a", Style::default())], vec![span("1", code)]],
+                ],
+            }]
+        );
+        assert_eq!(
+            parse("<table><tr><td>a</td></tr></table>"),
+            vec![Block::Table {
+                header: false,
+                rows: vec![vec![vec![span("a", Style::default())]]],
+            }]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+pub mod html;
+
+/// Link schemes that are safe to open
+const LINK_SCHEMES: &[&str] = &["https", "http", "ftp", "mailto", "magnet"];
+
+/// Inline formatting of a run of text
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub code: bool,
+    /// Text color as RGB
+    pub color: Option<[u8; 3]>,
+    /// Where the text links to
+    pub link: Option<String>,
+    /// Hidden until the reader asks to see it
+    pub spoiler: bool,
+}
+
+/// Text with the same formatting throughout
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+/// Part of a message laid out on its own lines
+#[derive(Clone, Debug, PartialEq)]
+pub enum Block {
+    Paragraph(Vec<Span>),
+    /// Heading with its level from 1 to 6
+    Heading(u8, Vec<Span>),
+    Code {
+        language: Option<String>,
+        code: String,
+    },
+    Quote(Vec<Block>),
+    /// Numbered from `start` if the list is ordered
+    List {
+        start: Option<u64>,
+        items: Vec<Vec<Block>>,
+    },
+    /// Rows of cells, the first row being the header if `header` is set
+    Table {
+        header: bool,
+        rows: Vec<Vec<Vec<Span>>>,
+    },
+    Rule,
+}
+
+/// Adds text to a paragraph, merging it with the last span if the formatting is the same
+pub fn push_span(spans: &mut Vec<Span>, text: &str, style: &Style) {
+    if text.is_empty() {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if &last.style == style => last.text.push_str(text),
+        _ => spans.push(Span {
+            text: text.to_owned(),
+            style: style.clone(),
+        }),
+    }
+}
+
+/// Whether a link uses a scheme that is safe to open
+pub fn safe_link(href: &str) -> bool {
+    match href.find(':') {
+        Some(end) => LINK_SCHEMES.contains(&href[..end].to_ascii_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// Number of buttons needed to draw the blocks, for links, hidden spoilers and copying code
+pub fn button_count(blocks: &[Block]) -> usize {
+    let count = |spans: &[Span]| {
+        spans
+            .iter()
+            .filter(|span| span.style.link.is_some() || span.style.spoiler)
+            .count()
+    };
+    blocks
+        .iter()
+        .map(|block| match block {
+            Block::Paragraph(spans) | Block::Heading(_, spans) => count(spans),
+            Block::Quote(blocks) => button_count(blocks),
+            Block::List { items, .. } => items.iter().map(|item| button_count(item)).sum(),
+            Block::Table { rows, .. } => rows.iter().flatten().map(|cell| count(cell)).sum(),
+            Block::Code { .. } => 1,
+            Block::Rule => 0,
+        })
+        .sum()
+}
+
+/// Where the links in the blocks point to
+pub fn links(blocks: &[Block]) -> Vec<&str> {
+    let mut links = Vec::new();
+    collect_links(blocks, &mut links);
+    links
+}
+
+fn collect_links<'a>(blocks: &'a [Block], links: &mut Vec<&'a str>) {
+    for block in blocks {
+        let spans: Vec<&Vec<Span>> = match block {
+            Block::Paragraph(spans) | Block::Heading(_, spans) => vec![spans],
+            Block::Table { rows, .. } => rows.iter().flatten().collect(),
+            Block::Quote(blocks) => {
+                collect_links(blocks, links);
+                continue;
+            }
+            Block::List { items, .. } => {
+                for item in items {
+                    collect_links(item, links);
+                }
+                continue;
+            }
+            Block::Code { .. } | Block::Rule => continue,
+        };
+        links.extend(
+            spans
+                .into_iter()
+                .flatten()
+                .filter_map(|span| span.style.link.as_deref()),
+        );
+    }
+}
@@ -3,5 +3,22 @@ table! {
         user_id -> Text,
         access_token -> Text,
         device_id -> Text,
+        homeserver -> Text,
+        filter_id -> Nullable<Text>,
     }
 }
+
+table! {
+    room_events (user_id, event_id) {
+        user_id -> Text,
+        event_id -> Text,
+        room_id -> Text,
+        origin_server_ts -> BigInt,
+        json -> Text,
+        prev_batch -> Nullable<Text>,
+        next_batch -> Nullable<Text>,
+        thread_root -> Nullable<Text>,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(matrix_session, room_events,);
@@ -1,12 +1,24 @@
-use iced::{executor, keyboard, Application, Command, Subscription};
+use iced::{executor, keyboard, mouse, Application, Command, Subscription};
 use iced_native::Event;
 
-use crate::{matrix::subscriber::MatrixSync, session::get_session};
+use crate::{
+    config::Config,
+    database::{connection::establish_connection, session::get_sessions},
+    matrix::subscriber::MatrixSync,
+    paths::Paths,
+    session::import_toml_sessions,
+};
 
-use super::{HomePage, LoginPage, Messages, VerifyPage};
+use super::{HomePage, LoginPage, Messages, RegisterPage, VerifyPage};
+
+pub struct Flags {
+    pub paths: Paths,
+    pub config: Config,
+}
 
 pub enum Elementary {
     LoginPage(LoginPage),
+    RegisterPage(RegisterPage),
     HomePage(HomePage),
     VerifyPage(VerifyPage),
 }
@@ -16,25 +28,39 @@ impl Application for Elementary {
 
     type Message = Messages;
 
-    type Flags = ();
-
-    fn new(_flags: Self::Flags) -> (Self, iced::Command<Self::Message>) {
-        let session_result = get_session();
+    type Flags = Flags;
 
-        match session_result {
-            Ok(Some(session)) => {
-                let command = Command::perform(
-                    async move { crate::matrix::login::restore_login(session).await },
-                    |result| match result {
-                        Ok((client, session)) => Self::Message::LoginResult(client, session),
-                        Err(e) => Self::Message::LoginFailed(e.to_string()),
-                    },
-                );
-
-                (Elementary::LoginPage(LoginPage::default()), command)
+    fn new(Flags { paths, config }: Self::Flags) -> (Self, iced::Command<Self::Message>) {
+        let conn = match establish_connection(&paths) {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!("{:#}", e);
+                let mut login = LoginPage::new(paths, config);
+                login.error = Some(format!("{:#}", e));
+                return (Elementary::LoginPage(login), Command::none());
             }
-            _ => (Elementary::LoginPage(LoginPage::default()), Command::none()),
+        };
+        if let Err(e) = import_toml_sessions(&paths, &conn) {
+            tracing::error!("Failed to import the session file: {}", e);
         }
+        let sessions = get_sessions(&conn).unwrap_or_default();
+
+        // The first restored account opens the home page, the others are added to it
+        let commands = sessions.into_iter().map(|session| {
+            let paths = paths.clone();
+            Command::perform(
+                async move { crate::matrix::login::restore_login(paths, session).await },
+                |result| match result {
+                    Ok((client, session)) => Self::Message::LoginResult(client, session),
+                    Err(e) => Self::Message::LoginFailed(e.to_string()),
+                },
+            )
+        });
+
+        (
+            Elementary::LoginPage(LoginPage::new(paths, config)),
+            Command::batch(commands),
+        )
     }
 
     fn title(&self) -> String {
@@ -57,16 +83,32 @@ impl Application for Elementary {
                             }),
                             _ => None,
                         },
+                        Event::Mouse(mouse::Event::ButtonPressed(_)) => {
+                            Some(Self::Message::CheckFocus)
+                        }
                         _ => None,
                     }
                 },
             ),
+            Elementary::RegisterPage(_) => Subscription::none(),
             Elementary::HomePage(home) => {
-                MatrixSync::subscription(home.client.clone()).map(Self::Message::Sync)
-            }
-            Elementary::VerifyPage(verify) => {
-                MatrixSync::subscription(verify.client.clone()).map(Self::Message::Sync)
+                Subscription::batch(home.accounts.iter().map(|account| {
+                    MatrixSync::subscription(
+                        home.paths.clone(),
+                        account.client.clone(),
+                        account.session.user_id.clone(),
+                        home.config.sync.timeout(),
+                    )
+                    .map(|(user_id, event)| Self::Message::Sync(user_id, event))
+                }))
             }
+            Elementary::VerifyPage(verify) => MatrixSync::subscription(
+                verify.paths.clone(),
+                verify.client.clone(),
+                verify.session.user_id.clone(),
+                verify.config.sync.timeout(),
+            )
+            .map(|(user_id, event)| Self::Message::Sync(user_id, event)),
         }
     }
 
@@ -80,7 +122,22 @@ impl Application for Elementary {
 
                 command
             }
-            Elementary::HomePage(home) => home.update(message),
+            Elementary::RegisterPage(register) => {
+                let (command, page_to_move) = register.update(message);
+                if let Some(page) = page_to_move {
+                    *self = page;
+                }
+
+                command
+            }
+            Elementary::HomePage(home) => {
+                let (command, page_to_move) = home.update(message);
+                if let Some(page) = page_to_move {
+                    *self = page;
+                }
+
+                command
+            }
             Elementary::VerifyPage(verify) => {
                 let (command, page_to_move) = verify.update(message);
                 if let Some(page) = page_to_move {
@@ -95,6 +152,7 @@ impl Application for Elementary {
     fn view(&mut self) -> iced::Element<'_, Self::Message> {
         match self {
             Elementary::LoginPage(login) => login.view(),
+            Elementary::RegisterPage(register) => register.view(),
             Elementary::HomePage(home) => home.view(),
             Elementary::VerifyPage(verify) => verify.view(),
         }
@@ -0,0 +1,207 @@
+use iced::{
+    button, image, Align, Button, Column, Container, Element, Image, Length, Row, Rule, Text,
+};
+
+use crate::{
+    matrix::permalink::{self, Permalink},
+    rich_text::{Block, Span},
+    theme::style::Theme,
+    utils::highlight::highlight,
+};
+
+use super::{verify::ICONS, Messages};
+
+const LINK_COLOR: [f32; 3] = [0.45, 0.54, 0.85];
+const DIMMED_COLOR: [f32; 3] = [0.6, 0.6, 0.6];
+/// Text size of headings by level
+const HEADING_SIZES: [u16; 6] = [28, 24, 22, 20, 18, 16];
+const TEXT_SIZE: u16 = 20;
+
+/// How a link to a user or room is shown
+pub struct Pill {
+    pub name: String,
+    pub avatar: Option<image::Handle>,
+    pub on_press: Messages,
+}
+
+/// Draws rich text. Iced can't change the weight or slant of a font, so bold, italic and
+/// underline aren't drawn
+pub struct Renderer<'a, I, P>
+where
+    I: Iterator<Item = &'a mut button::State>,
+    P: Fn(&Permalink, &str) -> Pill,
+{
+    /// A state for every button in the blocks, see `rich_text::button_count`
+    pub buttons: I,
+    pub theme: Theme,
+    /// Whether spoilers are shown
+    pub revealed: bool,
+    /// Sent when a hidden spoiler is clicked
+    pub reveal: Messages,
+    /// Looks up what a matrix.to link with its url points to
+    pub pill: P,
+}
+
+impl<'a, I, P> Renderer<'a, I, P>
+where
+    I: Iterator<Item = &'a mut button::State>,
+    P: Fn(&Permalink, &str) -> Pill,
+{
+    pub fn blocks(&mut self, blocks: &[Block]) -> Column<'a, Messages> {
+        blocks
+            .iter()
+            .fold(Column::new().spacing(5), |column, block| {
+                column.push(self.block(block))
+            })
+    }
+
+    fn block(&mut self, block: &Block) -> Element<'a, Messages> {
+        match block {
+            Block::Paragraph(spans) => self.spans(spans, TEXT_SIZE).into(),
+            Block::Heading(level, spans) => {
+                let size = HEADING_SIZES[(*level as usize).saturating_sub(1).min(5)];
+                self.spans(spans, size).into()
+            }
+            Block::Code { language, code } => {
+                let mut lines = Column::new();
+                match highlight(code, language.as_deref(), self.theme.code_theme()) {
+                    Some(highlighted) => {
+                        for line in highlighted {
+                            lines = lines.push(line.into_iter().fold(
+                                Row::new(),
+                                |row, ([r, g, b], text)| {
+                                    row.push(
+                                        Text::new(text)
+                                            .font(ICONS)
+                                            .color(iced::Color::from_rgb8(r, g, b)),
+                                    )
+                                },
+                            ));
+                        }
+                    }
+                    None => lines = lines.push(Text::new(code.as_str()).font(ICONS)),
+                }
+                let mut header = Row::new().push(
+                    Text::new(language.as_deref().unwrap_or_default())
+                        .size(12)
+                        .color(DIMMED_COLOR)
+                        .width(Length::Fill),
+                );
+                if let Some(state) = self.buttons.next() {
+                    header = header.push(
+                        Button::new(state, Text::new("Copy").size(12))
+                            .style(self.theme)
+                            .on_press(Messages::CopyToClipboard(code.clone())),
+                    );
+                }
+                Container::new(Column::new().spacing(5).push(header).push(lines))
+                    .padding(5)
+                    .width(Length::Fill)
+                    .style(self.theme.code_block())
+                    .into()
+            }
+            Block::Quote(blocks) => Container::new(self.blocks(blocks))
+                .padding(5)
+                .style(self.theme)
+                .into(),
+            Block::List { start, items } => {
+                let mut list = Column::new().spacing(2);
+                for (i, item) in items.iter().enumerate() {
+                    let marker = match start {
+                        Some(start) => format!("{}.", start + i as u64),
+                        None => String::from("•"),
+                    };
+                    list = list.push(
+                        Row::new()
+                            .spacing(5)
+                            .push(Text::new(marker).size(TEXT_SIZE))
+                            .push(self.blocks(item)),
+                    );
+                }
+                list.into()
+            }
+            Block::Table { rows, .. } => {
+                let mut table = Column::new().spacing(2);
+                for row in rows {
+                    let mut cells = Row::new().spacing(10);
+                    for cell in row {
+                        cells = cells.push(
+                            Container::new(self.spans(cell, TEXT_SIZE))
+                                .width(Length::FillPortion(1)),
+                        );
+                    }
+                    table = table.push(cells);
+                }
+                table.into()
+            }
+            Block::Rule => Rule::horizontal(1).into(),
+        }
+    }
+
+    fn spans(&mut self, spans: &[Span], size: u16) -> Row<'a, Messages> {
+        let mut row = Row::new();
+        for span in spans.iter() {
+            let style = &span.style;
+            let mut text = Text::new(span.text.as_str()).size(size);
+            if style.code {
+                text = text.font(ICONS);
+            }
+            if let Some([r, g, b]) = style.color {
+                text = text.color(iced::Color::from_rgb8(r, g, b));
+            }
+            if style.strikethrough {
+                text = text.color(DIMMED_COLOR);
+            }
+
+            if style.spoiler && !self.revealed {
+                row = match self.buttons.next() {
+                    Some(state) => row.push(
+                        Button::new(state, Text::new("Spoiler").size(size).color(DIMMED_COLOR))
+                            .padding(0)
+                            .style(self.theme)
+                            .on_press(self.reveal.clone()),
+                    ),
+                    None => row.push(Text::new("Spoiler").size(size).color(DIMMED_COLOR)),
+                };
+                continue;
+            }
+            let state = match style.link {
+                Some(_) => self.buttons.next(),
+                None => None,
+            };
+            row = match (&style.link, state) {
+                (Some(link), Some(state)) => match permalink::parse(link) {
+                    Some(permalink) => {
+                        let pill = (self.pill)(&permalink, link);
+                        row.push(pill_button(state, pill, size, self.theme))
+                    }
+                    None => row.push(
+                        Button::new(state, text.color(LINK_COLOR))
+                            .padding(0)
+                            .style(self.theme)
+                            .on_press(Messages::OpenLink(link.clone())),
+                    ),
+                },
+                _ => row.push(text),
+            };
+        }
+        row
+    }
+}
+
+/// A link to a user or room drawn with its name and avatar
+fn pill_button(state: &mut button::State, pill: Pill, size: u16, theme: Theme) -> Button<Messages> {
+    let mut content = Row::new().spacing(3).align_items(Align::Center);
+    if let Some(avatar) = pill.avatar {
+        content = content.push(
+            Image::new(avatar)
+                .width(Length::Units(size))
+                .height(Length::Units(size)),
+        );
+    }
+    content = content.push(Text::new(pill.name).size(size).color(LINK_COLOR));
+    Button::new(state, content)
+        .padding(2)
+        .style(theme)
+        .on_press(pill.on_press)
+}
@@ -1,34 +1,284 @@
-use futures::executor::block_on;
+use std::convert::TryFrom;
+
+use copypasta::{ClipboardContext, ClipboardProvider};
 use iced::{
-    container, Align, Button, Column, Command, Container, Element, Image, Length, Row, Rule,
-    Scrollable, Text, TextInput,
+    container, tooltip, Align, Button, Column, Command, Container, Element, Image, Length, Row,
+    Rule, Scrollable, Text, TextInput, Tooltip,
 };
 use matrix_sdk::{
     events::{
+        custom::CustomEventContent,
         room::{member::MembershipState, message::MessageEventContent},
-        AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent, AnyStateEvent,
+        AnyMessageEvent, AnyMessageEventContent, AnyRedactedMessageEvent, AnyRoomEvent,
+        AnyStateEvent, MessageEvent,
     },
-    identifiers::{RoomId, UserId},
+    identifiers::{EventId, RoomId, UserId},
 };
 
 use crate::{
+    config::NotificationConfig,
+    database::events::{add_events, get_events, set_thread_root, update_cache},
     matrix::{
-        message::{parse_mxc, AnyMessageEventExt, AnyRoomEventExt},
-        room::{get_sender_details, partition_rooms, RoomEntry},
-        subscriber::MatrixEvents,
+        login::{forget_session, logout},
+        message::{
+            content_body, parse_mxc, replaced_event, reply_content, reply_to, strip_reply_fallback,
+            thread_content, thread_root, AnyMessageEventExt, AnyRoomEventExt, CacheUpdate,
+            Direction, Timeline,
+        },
+        permalink::{self, Permalink},
+        room::{
+            fetch_event, get_sender_details, partition_rooms, power_levels, react, redact,
+            resolve_member, thread_replies, token_before, RoomEntry,
+        },
+        subscriber::{MatrixEvents, SyncStatus},
     },
+    rich_text::{button_count, links},
     theme::{dark, style::Theme},
+    utils::markdown,
 };
 
 use matrix_sdk::api::r0::media::get_content::Request as ImageRequest;
 use matrix_sdk::api::r0::message::get_message_events::Request as MessageRequest;
 
-use super::{HomePage, Messages};
+use super::{
+    elementary::Elementary,
+    formatted::{Pill, Renderer},
+    verify::ICONS,
+    Account, HomePage, LoginPage, Messages,
+};
+
+/// Reactions offered by the quick reaction picker
+const QUICK_REACTIONS: [&str; 6] = ["👍", "❤️", "😄", "🎉", "😮", "😢"];
 
 impl HomePage {
-    pub fn update(&mut self, message: Messages) -> iced::Command<Messages> {
+    /// Fetches the room list of the selected account
+    pub fn load_rooms(&self) -> Command<Messages> {
+        let mut commands: Vec<Command<Messages>> = Vec::new();
+        for room in self.client.joined_rooms().into_iter() {
+            let room = std::sync::Arc::new(room);
+            let r = room.clone();
+            let user_id = self.session.user_id.clone();
+            let command: Command<_> = async move {
+                let entry = RoomEntry::from_sdk(&r, &user_id).await;
+                Messages::ResetRoom(user_id, r.room_id().to_owned(), entry)
+            }
+            .into();
+            if let Some(url) = room.avatar_url() {
+                commands.push(async { Messages::FetchImage(url) }.into())
+            }
+            commands.push(command);
+        }
+        Command::batch(commands)
+    }
+
+    /// Makes `account` the selected account and reloads the room list from it
+    fn switch_account(&mut self, account: Account) -> Command<Messages> {
+        self.client = account.client;
+        self.session = account.session;
+        self.rooms.clear();
+        self.selected = None;
+        self.sync_token = Default::default();
+        self.draft = Default::default();
+        self.thread = None;
+        self.load_rooms()
+    }
+
+    /// Asks the server for the power levels of a room no sync has told us about yet
+    fn load_power_levels(&self, room_id: &RoomId) -> Command<Messages> {
+        let client = self.client.clone();
+        let user_id = self.session.user_id.clone();
+        let room_id = room_id.clone();
+        async move {
+            let levels = match power_levels(&client, &room_id).await {
+                Ok(levels) => Some(levels),
+                Err(e) => {
+                    tracing::warn!("Loading power levels of {} failed: {}", room_id, e);
+                    None
+                }
+            };
+            Messages::PowerLevelsLoaded(user_id, room_id, levels)
+        }
+        .into()
+    }
+
+    /// Looks up the users that messages in a room link to, for the view to show who they are
+    fn resolve_members(&mut self, room_id: &RoomId) -> Command<Messages> {
+        let room = match self.rooms.get_mut(room_id) {
+            Some(room) => room,
+            None => return Command::none(),
+        };
+        let users: Vec<UserId> = room
+            .timeline
+            .events()
+            .filter_map(|event| room.timeline.body(event.event_id()))
+            .flat_map(links)
+            .filter_map(|link| match permalink::parse(link) {
+                Some(Permalink::User(user_id)) => Some(user_id),
+                _ => None,
+            })
+            .filter(|user_id| !room.members.contains_key(user_id))
+            .collect();
+        let mut commands = Vec::new();
+        for user_id in users {
+            if !room.resolving.insert(user_id.clone()) {
+                continue;
+            }
+            let client = self.client.clone();
+            let account = self.session.user_id.clone();
+            let room_id = room_id.clone();
+            commands.push(
+                async move {
+                    let member = resolve_member(&client, &room_id, &user_id).await;
+                    Messages::MemberResolved(account, room_id, user_id, member)
+                }
+                .into(),
+            );
+        }
+        Command::batch(commands)
+    }
+
+    /// Requests the messages of a room starting at the `from` token
+    fn paginate(&mut self, id: RoomId, from: String, direction: Direction) -> Command<Messages> {
+        let room = self.rooms.entry(id.clone()).or_default();
+        room.timeline.loading = true;
+        let client = self.client.clone();
+        let user_id = self.session.user_id.clone();
+        let limit = self.config.timeline.backfill_limit;
+        async move {
+            let mut request = match direction {
+                Direction::Backward => MessageRequest::backward(&id, &from),
+                Direction::Forward => MessageRequest::forward(&id, &from),
+            };
+            request.limit = limit.into();
+            match client.room_messages(request).await {
+                Ok(response) => Messages::BackFilled(user_id, id, from, direction, response),
+                Err(e) => Messages::BackFillFailed(user_id, id, e.to_string()),
+            }
+        }
+        .into()
+    }
+
+    /// Total amount of unread notifications across every room of an account
+    fn unread_count(account: &Account, notifications: &NotificationConfig) -> u64 {
+        if !notifications.enabled {
+            return 0;
+        }
+        account
+            .client
+            .joined_rooms()
+            .iter()
+            .map(|room| {
+                let counts = room.unread_notification_counts();
+                if notifications.mentions_only {
+                    counts.highlight_count
+                } else {
+                    counts.notification_count
+                }
+            })
+            .sum()
+    }
+
+    pub fn update(&mut self, message: Messages) -> (Command<Messages>, Option<Elementary>) {
         match message {
-            Messages::Sync(event) => match event {
+            // Answers to requests made for an account we've switched away from since
+            Messages::ResetRoom(ref user_id, ..)
+            | Messages::RoomName(ref user_id, ..)
+            | Messages::PrevBatchFound(ref user_id, ..)
+            | Messages::BackFilled(ref user_id, ..)
+            | Messages::BackFillFailed(ref user_id, ..)
+            | Messages::MembersLoaded(ref user_id, ..)
+            | Messages::ThreadLoaded(ref user_id, ..)
+            | Messages::ThreadLoadFailed(ref user_id, ..)
+            | Messages::MemberResolved(ref user_id, ..)
+            | Messages::PowerLevelsLoaded(ref user_id, ..)
+                if user_id != &self.session.user_id => {}
+            Messages::Sync(user_id, MatrixEvents::LoggedOut) => {
+                // The token is already invalid, so only forget the account locally
+                if let Some(account) = self
+                    .accounts
+                    .iter()
+                    .find(|account| account.session.user_id == user_id)
+                {
+                    let paths = self.paths.clone();
+                    let session = account.session.clone();
+                    return (
+                        Command::perform(
+                            async move { forget_session(&paths, &session).map(|_| session.user_id) },
+                            |result| match result {
+                                Ok(user_id) => Messages::LoggedOut(user_id),
+                                Err(e) => Messages::LoginFailed(e.to_string()),
+                            },
+                        ),
+                        None,
+                    );
+                }
+            }
+            // The SDK keeps the state of the other accounts up to date by itself, we only
+            // cache their timelines so their rooms are complete once we switch to them
+            Messages::Sync(
+                user_id,
+                MatrixEvents::Timeline {
+                    room_id,
+                    events,
+                    limited,
+                    prev_batch,
+                    thread_roots,
+                    ..
+                },
+            ) if user_id != self.session.user_id => {
+                // Skipped events leave a gap before the first event of the timeline
+                let token = if limited { prev_batch.as_deref() } else { None };
+                if let Err(e) = add_events(&self.conn, &user_id, &room_id, &events, token) {
+                    tracing::warn!("Caching events failed: {}", e);
+                }
+                for (event_id, root) in thread_roots {
+                    if let Err(e) = set_thread_root(&self.conn, &user_id, &event_id, &root) {
+                        tracing::warn!("Caching thread failed: {}", e);
+                    }
+                }
+            }
+            // Every account keeps its status, it's only sent when it changes
+            Messages::Sync(user_id, MatrixEvents::Status(status)) => {
+                self.sync_status.insert(user_id, status);
+            }
+            Messages::Sync(user_id, _) if user_id != self.session.user_id => {}
+            Messages::Sync(
+                user_id,
+                MatrixEvents::Timeline {
+                    room_id,
+                    events,
+                    limited,
+                    prev_batch,
+                    next_batch,
+                    thread_roots,
+                },
+            ) => {
+                let room = self.rooms.entry(room_id.clone()).or_default();
+                let update = room
+                    .timeline
+                    .sync(events.clone(), limited, prev_batch, next_batch);
+                if let Err(e) = update_cache(&self.conn, &user_id, &room_id, &update) {
+                    tracing::warn!("Caching events failed: {}", e);
+                }
+                for (event_id, root) in thread_roots {
+                    if let Err(e) = set_thread_root(&self.conn, &user_id, &event_id, &root) {
+                        tracing::warn!("Caching thread failed: {}", e);
+                    }
+                    room.timeline.set_thread_root(&event_id, root);
+                }
+                let mut commands: Vec<Command<_>> = events
+                    .into_iter()
+                    .map(|event| {
+                        self.update(Messages::Sync(user_id.clone(), MatrixEvents::Room(event)))
+                            .0
+                    })
+                    .collect();
+                if self.selected.as_ref() == Some(&room_id) {
+                    commands.push(self.resolve_members(&room_id));
+                }
+                return (Command::batch(commands), None);
+            }
+            Messages::Sync(_, event) => match event {
                 MatrixEvents::Room(room_event) => match room_event {
                     matrix_sdk::events::AnyRoomEvent::Message(message_event) => {
                         let mut commands = Vec::new();
@@ -57,7 +307,7 @@ impl HomePage {
                             commands.push(marker_cmd.into());
                         };
 
-                        return Command::batch(commands);
+                        return (Command::batch(commands), None);
                     }
                     matrix_sdk::events::AnyRoomEvent::State(event) => {
                         match event {
@@ -70,11 +320,20 @@ impl HomePage {
                                 let room = self.rooms.entry(id.clone()).or_default();
                                 room.display_name = name.content.name().map(String::from);
                                 let client = self.client.clone();
-                                return async move {
-                                    let joined = client.get_joined_room(&id).unwrap();
-                                    Messages::RoomName(id, joined.display_name().await.unwrap())
-                                }
-                                .into();
+                                let user_id = self.session.user_id.clone();
+                                return (
+                                    async move {
+                                        let joined = client.get_joined_room(&id).unwrap();
+                                        let name = joined.display_name().await.unwrap();
+                                        Messages::RoomName(user_id, id, name)
+                                    }
+                                    .into(),
+                                    None,
+                                );
+                            }
+                            AnyStateEvent::RoomPowerLevels(ref levels) => {
+                                let room = self.rooms.entry(levels.room_id.clone()).or_default();
+                                room.set_power_levels(&levels.content, &self.session.user_id);
                             }
                             AnyStateEvent::RoomTopic(ref topic) => {
                                 let room = self.rooms.entry(topic.room_id.clone()).or_default();
@@ -84,32 +343,49 @@ impl HomePage {
                                 let room = self.rooms.entry(avatar.room_id.clone()).or_default();
                                 if let Some(url) = room.avatar.clone() {
                                     room.avatar = Some(url.clone());
-                                    return async { Messages::FetchImage(url) }.into();
+                                    return (async { Messages::FetchImage(url) }.into(), None);
                                 }
                             }
                             AnyStateEvent::RoomCreate(ref create) => {
                                 // Add room to the entry list
                                 let joined = self.client.get_joined_room(&create.room_id).unwrap();
                                 let id = create.room_id.clone();
-                                return async move {
-                                    let entry = RoomEntry::from_sdk(&joined).await;
-                                    Messages::ResetRoom(id, entry)
-                                }
-                                .into();
+                                let user_id = self.session.user_id.clone();
+                                return (
+                                    async move {
+                                        let entry = RoomEntry::from_sdk(&joined, &user_id).await;
+                                        Messages::ResetRoom(user_id, id, entry)
+                                    }
+                                    .into(),
+                                    None,
+                                );
                             }
                             AnyStateEvent::RoomMember(ref member) => {
+                                // Their name or avatar may have changed
+                                if let Some(room) = self.rooms.get_mut(&member.room_id) {
+                                    room.members.retain(|user_id, _| {
+                                        user_id.as_str() != member.state_key.as_str()
+                                    });
+                                }
                                 let client = self.client.clone();
                                 // If we left a room, remove it from the RoomEntry list
                                 if member.state_key == self.session.user_id {
                                     match member.content.membership {
                                         MembershipState::Join => {
                                             let id = member.room_id.clone();
-                                            return async move {
-                                                let joined = client.get_joined_room(&id).unwrap();
-                                                let entry = RoomEntry::from_sdk(&joined).await;
-                                                Messages::ResetRoom(id, entry)
-                                            }
-                                            .into();
+                                            let user_id = self.session.user_id.clone();
+                                            return (
+                                                async move {
+                                                    let joined =
+                                                        client.get_joined_room(&id).unwrap();
+                                                    let entry =
+                                                        RoomEntry::from_sdk(&joined, &user_id)
+                                                            .await;
+                                                    Messages::ResetRoom(user_id, id, entry)
+                                                }
+                                                .into(),
+                                                None,
+                                            );
                                         }
                                         MembershipState::Leave => {
                                             // Deselect room if we're leaving selected room
@@ -117,7 +393,7 @@ impl HomePage {
                                                 self.selected = None;
                                             }
                                             self.rooms.remove(&member.room_id);
-                                            return Command::none();
+                                            return (Command::none(), None);
                                         }
                                         _ => (),
                                     }
@@ -129,14 +405,16 @@ impl HomePage {
                     matrix_sdk::events::AnyRoomEvent::RedactedMessage(_) => {}
                     matrix_sdk::events::AnyRoomEvent::RedactedState(_) => {}
                 },
-                MatrixEvents::ToDevice(_) => {}
+                MatrixEvents::Left(room_id) => {
+                    if self.selected.as_ref() == Some(&room_id) {
+                        self.selected = None;
+                    }
+                    self.rooms.remove(&room_id);
+                }
+                _ => {}
             },
+            // The timeline already has the message, only fetch what it needs to be displayed
             Messages::RoomMessage(message_event) => {
-                let room = self
-                    .rooms
-                    .entry(message_event.room_id().clone())
-                    .or_default();
-
                 let mut commands = Vec::new();
 
                 if let Some(joined_room) = self
@@ -153,11 +431,38 @@ impl HomePage {
                 }
 
                 match message_event.clone() {
+                    // Reactions are collected by the timeline
                     AnyMessageEvent::Reaction(_) => {}
                     AnyMessageEvent::RoomEncrypted(_) => {}
                     AnyMessageEvent::RoomMessage(message) => {
-                        room.message_list.push(message.clone());
-
+                        // Replies show a quote of the message they reply to
+                        if let Some(original) = reply_to(&message.content) {
+                            let missing = self.rooms.get(&message.room_id).map_or(false, |room| {
+                                room.timeline.replied(original).is_none()
+                                    && !room.timeline.is_unavailable(original)
+                            });
+                            if missing {
+                                let client = self.client.clone();
+                                let room_id = message.room_id.clone();
+                                let original = original.clone();
+                                commands.push(
+                                    async move {
+                                        match fetch_event(&client, &room_id, &original).await {
+                                            Ok(event) => Messages::FetchedEvent(room_id, event),
+                                            Err(e) => {
+                                                tracing::warn!(
+                                                    "Fetching {} failed: {}",
+                                                    original,
+                                                    e
+                                                );
+                                                Messages::FetchEventFailed(room_id, original)
+                                            }
+                                        }
+                                    }
+                                    .into(),
+                                );
+                            }
+                        }
                         if let MessageEventContent::Image(image_message_content) = message.content {
                             if let Some(image_url) = image_message_content.url {
                                 commands
@@ -171,46 +476,146 @@ impl HomePage {
                     _ => {}
                 };
 
-                return Command::batch(commands);
+                return (Command::batch(commands), None);
             }
-            Messages::ResetRoom(id, room) => {
-                self.rooms.insert(id.clone(), room);
-                return async move { Messages::BackFill(id) }.into();
+            Messages::ResetRoom(_, id, mut room) => {
+                match get_events(&self.conn, &self.session.user_id, &id) {
+                    Ok(events) => room.timeline = Timeline::from_cached(events),
+                    Err(e) => tracing::warn!("Loading cached events failed: {}", e),
+                }
+                // Only go to the server if we have nothing cached, the sync brings the rest
+                let mut commands: Vec<Command<_>> = room
+                    .timeline
+                    .events()
+                    .filter_map(|event| match event {
+                        AnyRoomEvent::Message(message_event) => {
+                            let message_event = message_event.clone();
+                            Some(async move { Messages::RoomMessage(message_event) }.into())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                if room.timeline.is_empty() {
+                    let id = id.clone();
+                    commands.push(async move { Messages::BackFill(id) }.into());
+                }
+                // A sync may have brought the power levels before the entry was built
+                if let Some(old) = self.rooms.get(&id).filter(|old| old.redact_level.is_some()) {
+                    room.redact_level = old.redact_level;
+                    room.own_power = old.own_power;
+                } else if self.selected.as_ref() == Some(&id) {
+                    commands.push(self.load_power_levels(&id));
+                }
+                self.rooms.insert(id, room);
+                return (Command::batch(commands), None);
             }
-            Messages::RoomName(id, name) => {
+            Messages::RoomName(_, id, name) => {
                 if let Some(room) = self.rooms.get_mut(&id) {
                     room.name = name;
                 }
             }
             Messages::BackFill(id) => {
-                let room = self.rooms.entry(id.clone()).or_default();
-                room.messages.loading = true;
-                let client = self.client.clone();
-                let token = match room.messages.end.clone() {
-                    Some(end) => end,
-                    None => client
-                        .get_joined_room(&id)
-                        .unwrap()
-                        .last_prev_batch()
-                        .unwrap_or_else(|| self.sync_token.clone()),
+                let sync_token = self.sync_token.clone();
+                // The room may belong to an account we've switched away from
+                let room = match self.rooms.get_mut(&id) {
+                    Some(room) => room,
+                    None => return (Command::none(), None),
                 };
-                return async move {
-                    let mut request = MessageRequest::backward(&id, &token);
-                    request.limit = matrix_sdk::uint!(30);
-                    match client.room_messages(request).await {
-                        Ok(response) => Messages::BackFilled(id, response),
-                        Err(e) => Messages::LoginFailed(e.to_string()),
+                let token = match room.timeline.prev_batch() {
+                    Some(token) => token.to_owned(),
+                    // The tokens of the last sync only point before the newest messages
+                    None if room.timeline.is_empty() => {
+                        let token = self
+                            .client
+                            .get_joined_room(&id)
+                            .and_then(|joined| joined.last_prev_batch())
+                            .unwrap_or(sync_token);
+                        room.timeline.set_prev_batch(token.clone());
+                        token
                     }
+                    None if room.timeline.has_beginning() => return (Command::none(), None),
+                    // Cached before we kept tokens, ask the server where the oldest message is
+                    None => {
+                        let oldest = match room.timeline.events().next() {
+                            Some(event) => event.event_id().clone(),
+                            None => return (Command::none(), None),
+                        };
+                        room.timeline.loading = true;
+                        let client = self.client.clone();
+                        let user_id = self.session.user_id.clone();
+                        return (
+                            async move {
+                                match token_before(&client, &id, &oldest).await {
+                                    Ok(token) => {
+                                        Messages::PrevBatchFound(user_id, id, oldest, token)
+                                    }
+                                    Err(e) => Messages::BackFillFailed(user_id, id, e.to_string()),
+                                }
+                            }
+                            .into(),
+                            None,
+                        );
+                    }
+                };
+                return (self.paginate(id, token, Direction::Backward), None);
+            }
+            Messages::PrevBatchFound(_, id, event_id, token) => {
+                let room = match self.rooms.get_mut(&id) {
+                    Some(room) => room,
+                    None => return (Command::none(), None),
+                };
+                room.timeline.loading = false;
+                if !room
+                    .timeline
+                    .set_prev_batch_before(&event_id, token.clone())
+                {
+                    return (Command::none(), None);
+                }
+                let update = CacheUpdate {
+                    prev_batches: vec![(event_id, Some(token.clone()))],
+                    ..Default::default()
+                };
+                if let Err(e) = update_cache(&self.conn, &self.session.user_id, &id, &update) {
+                    tracing::warn!("Caching backfill token failed: {}", e);
                 }
-                .into();
+                return (self.paginate(id, token, Direction::Backward), None);
             }
-            Messages::BackFilled(id, response) => {
-                let room = self.rooms.get_mut(&id).unwrap();
-                room.messages.loading = false;
-                let events: Vec<AnyRoomEvent> = response
+            Messages::FillGap(id, from, direction) => {
+                return (self.paginate(id, from, direction), None);
+            }
+            Messages::BackFillFailed(_, id, e) => {
+                tracing::warn!("Loading older messages failed: {}", e);
+                if let Some(room) = self.rooms.get_mut(&id) {
+                    room.timeline.loading = false;
+                }
+            }
+            Messages::BackFilled(_, id, from, direction, response) => {
+                let room = match self.rooms.get_mut(&id) {
+                    Some(room) => room,
+                    None => return (Command::none(), None),
+                };
+                room.timeline.loading = false;
+                // The SDK drops thread relations, so read them before deserializing
+                let threads: Vec<(EventId, EventId)> = response
+                    .chunk
+                    .iter()
+                    .filter_map(|event| {
+                        let json: serde_json::Value =
+                            serde_json::from_str(event.json().get()).ok()?;
+                        let root = thread_root(&json)?;
+                        let id = EventId::try_from(json.get("event_id")?.as_str()?).ok()?;
+                        Some((id, root))
+                    })
+                    .collect();
+                let timeline: Vec<AnyRoomEvent> = response
                     .chunk
                     .into_iter()
                     .filter_map(|e| e.deserialize().ok())
+                    .collect();
+                // The state only tells us about members, it isn't part of the timeline
+                let events: Vec<AnyRoomEvent> = timeline
+                    .iter()
+                    .cloned()
                     .chain(
                         response
                             .state
@@ -218,14 +623,22 @@ impl HomePage {
                             .filter_map(|e| e.deserialize().ok().map(AnyRoomEvent::State)),
                     )
                     .collect();
-                if let Some(start) = response.start {
-                    room.messages.start = Some(start);
+                let update = room
+                    .timeline
+                    .paginate(&from, direction, timeline, response.end);
+                if let Err(e) = update_cache(&self.conn, &self.session.user_id, &id, &update) {
+                    tracing::warn!("Caching backfill failed: {}", e);
                 }
-                if let Some(end) = response.end {
-                    room.messages.end = Some(end);
+                for (event_id, root) in threads {
+                    if let Err(e) =
+                        set_thread_root(&self.conn, &self.session.user_id, &event_id, &root)
+                    {
+                        tracing::warn!("Caching thread failed: {}", e);
+                    }
+                    room.timeline.set_thread_root(&event_id, root);
                 }
 
-                let commands: Vec<Command<_>> = events
+                let mut commands: Vec<Command<_>> = events
                     .iter()
                     .filter_map(|event| {
                         if let AnyRoomEvent::Message(message_event) = event {
@@ -241,68 +654,451 @@ impl HomePage {
                         }
                     })
                     .collect();
+                if self.selected.as_ref() == Some(&id) {
+                    commands.push(self.resolve_members(&id));
+                }
 
-                return Command::batch(commands);
+                return (Command::batch(commands), None);
             }
             Messages::FetchImage(url) => {
                 let (server, path) = match parse_mxc(&url) {
                     Ok((server, path)) => (server, path),
-                    Err(e) => return async move { Messages::LoginFailed(e.to_string()) }.into(),
+                    Err(e) => {
+                        return (
+                            async move { Messages::LoginFailed(e.to_string()) }.into(),
+                            None,
+                        )
+                    }
                 };
 
                 let client = self.client.clone();
-                return async move {
-                    let request = ImageRequest::new(&path, &*server);
-                    let response = client.send(request, None).await;
-                    match response {
-                        Ok(response) => Messages::FetchedImage(
-                            url,
-                            iced::image::Handle::from_memory(response.file),
-                        ),
-                        Err(e) => Messages::LoginFailed(e.to_string()),
+                return (
+                    async move {
+                        let request = ImageRequest::new(&path, &*server);
+                        let response = client.send(request, None).await;
+                        match response {
+                            Ok(response) => Messages::FetchedImage(
+                                url,
+                                iced::image::Handle::from_memory(response.file),
+                            ),
+                            Err(e) => Messages::LoginFailed(e.to_string()),
+                        }
                     }
-                }
-                .into();
+                    .into(),
+                    None,
+                );
             }
             Messages::FetchedImage(url, handle) => {
                 self.images.insert(url, handle);
             }
             Messages::SelectRoom(id) => {
                 self.selected = Some(id.clone());
-                if self.rooms.get(&id).unwrap().messages.messages.is_empty() {
-                    return async move { Messages::BackFill(id) }.into();
+                self.thread = None;
+                self.edit_history = None;
+                self.redacting = None;
+                self.reacting_to = None;
+                self.replying_to = None;
+                self.profile = None;
+                let room = match self.rooms.get(&id) {
+                    Some(room) => room,
+                    None => return (Command::none(), None),
+                };
+                let mut commands = Vec::new();
+                if room.timeline.is_empty() {
+                    let id = id.clone();
+                    commands.push(async move { Messages::BackFill(id) }.into());
+                }
+                if !room.members_loaded {
+                    let id = id.clone();
+                    let client = self.client.clone();
+                    let user_id = self.session.user_id.clone();
+                    commands.push(
+                        async move {
+                            match client.room_members(&id).await {
+                                Ok(_) => Messages::MembersLoaded(user_id, id),
+                                Err(e) => Messages::LoginFailed(e.to_string()),
+                            }
+                        }
+                        .into(),
+                    );
+                }
+                if room.redact_level.is_none() {
+                    commands.push(self.load_power_levels(&id));
+                }
+                commands.push(self.resolve_members(&id));
+                return (Command::batch(commands), None);
+            }
+            Messages::PowerLevelsLoaded(_, id, levels) => {
+                if let (Some(room), Some(levels)) = (self.rooms.get_mut(&id), levels) {
+                    // Levels from a sync in the meantime are newer
+                    if room.redact_level.is_none() {
+                        room.set_power_levels(&levels, &self.session.user_id);
+                    }
+                }
+            }
+            Messages::ShowEditHistory(id) => self.edit_history = Some(id),
+            Messages::StartRedaction(id) => {
+                self.redacting = Some(id);
+                self.redact_reason = String::new();
+                self.redact_error = None;
+            }
+            Messages::RedactReasonChanged(reason) => self.redact_reason = reason,
+            Messages::CancelRedaction => self.redacting = None,
+            Messages::Redact => {
+                let (room_id, event_id) = match (self.selected.clone(), self.redacting.clone()) {
+                    (Some(room_id), Some(event_id)) => (room_id, event_id),
+                    _ => return (Command::none(), None),
+                };
+                let reason = Some(self.redact_reason.trim().to_owned()).filter(|r| !r.is_empty());
+                let client = self.client.clone();
+                return (
+                    async move {
+                        match redact(&client, &room_id, &event_id, reason.as_deref()).await {
+                            Ok(_) => Messages::Redacted(event_id),
+                            Err(e) => Messages::RedactFailed(event_id, e.to_string()),
+                        }
+                    }
+                    .into(),
+                    None,
+                );
+            }
+            // The sync brings the redaction itself
+            Messages::Redacted(id) => {
+                if self.redacting.as_ref() == Some(&id) {
+                    self.redacting = None;
+                }
+            }
+            Messages::RedactFailed(id, e) => {
+                tracing::warn!("Redaction failed: {}", e);
+                // Keep the dialog open so it can be tried again
+                if self.redacting.as_ref() == Some(&id) {
+                    self.redact_error = Some(e);
+                }
+            }
+            Messages::Reply(id) => {
+                self.replying_to = Some(id);
+                self.message_input.focus();
+            }
+            Messages::CancelReply => self.replying_to = None,
+            Messages::FetchedEvent(room_id, event) => {
+                if let Some(room) = self.rooms.get_mut(&room_id) {
+                    room.timeline.add_fetched(event);
+                }
+            }
+            Messages::FetchEventFailed(room_id, id) => {
+                if let Some(room) = self.rooms.get_mut(&room_id) {
+                    room.timeline.add_unavailable(id);
+                }
+            }
+            Messages::OpenLink(url) => {
+                if let Err(e) = open::that(&url) {
+                    tracing::warn!("Failed to open {}: {}", url, e);
+                }
+            }
+            Messages::CopyToClipboard(text) => {
+                let copied =
+                    ClipboardContext::new().and_then(|mut clipboard| clipboard.set_contents(text));
+                if let Err(e) = copied {
+                    tracing::warn!("Failed to copy to the clipboard: {}", e);
                 }
             }
+            Messages::ShowProfile(user_id) => self.profile = Some(user_id),
+            Messages::CloseProfile => self.profile = None,
+            Messages::MemberResolved(_, room_id, user_id, member) => {
+                let room = match self.rooms.get_mut(&room_id) {
+                    Some(room) => room,
+                    None => return (Command::none(), None),
+                };
+                room.resolving.remove(&user_id);
+                let avatar = member
+                    .as_ref()
+                    .and_then(|member| member.avatar.clone())
+                    .filter(|url| !self.images.contains_key(url));
+                room.members.insert(user_id, member);
+                if let Some(url) = avatar {
+                    return (async { Messages::FetchImage(url) }.into(), None);
+                }
+            }
+            Messages::OpenThread(root) => {
+                self.thread = Some(root);
+                self.thread_error = None;
+                self.thread_draft = String::new();
+                return (self.update(Messages::LoadThread).0, None);
+            }
+            Messages::CloseThread => self.thread = None,
+            Messages::LoadThread => {
+                let (room_id, root) = match (self.selected.clone(), self.thread.clone()) {
+                    (Some(room_id), Some(root)) => (room_id, root),
+                    _ => return (Command::none(), None),
+                };
+                let thread = match self.rooms.get_mut(&room_id) {
+                    Some(room) => room.timeline.thread_mut(&root),
+                    None => return (Command::none(), None),
+                };
+                if thread.loading || !thread.has_more() {
+                    return (Command::none(), None);
+                }
+                thread.loading = true;
+                let from = thread.next_batch.clone();
+                let client = self.client.clone();
+                let session = self.session.clone();
+                let limit = self.config.timeline.backfill_limit;
+                return (
+                    async move {
+                        let page = thread_replies(
+                            &client,
+                            &session,
+                            &room_id,
+                            &root,
+                            from.as_deref(),
+                            limit,
+                        )
+                        .await;
+                        match page {
+                            Ok((events, next_batch)) => Messages::ThreadLoaded(
+                                session.user_id,
+                                room_id,
+                                root,
+                                events,
+                                next_batch,
+                            ),
+                            Err(e) => Messages::ThreadLoadFailed(
+                                session.user_id,
+                                room_id,
+                                root,
+                                e.to_string(),
+                            ),
+                        }
+                    }
+                    .into(),
+                    None,
+                );
+            }
+            Messages::ThreadLoaded(_, room_id, root, events, next_batch) => {
+                if let Some(room) = self.rooms.get_mut(&room_id) {
+                    room.timeline.add_thread_page(root, events, next_batch);
+                }
+            }
+            Messages::ThreadLoadFailed(_, room_id, root, e) => {
+                tracing::warn!("Loading thread failed: {}", e);
+                if let Some(room) = self.rooms.get_mut(&room_id) {
+                    room.timeline.thread_mut(&root).loading = false;
+                }
+                if self.thread.as_ref() == Some(&root) {
+                    self.thread_error = Some(e);
+                }
+            }
+            Messages::SetThreadMessage(message) => self.thread_draft = message,
+            Messages::SendThreadMessage => {
+                let (room_id, root) = match (self.selected.clone(), self.thread.clone()) {
+                    (Some(room_id), Some(root)) => (room_id, root),
+                    _ => return (Command::none(), None),
+                };
+                if self.thread_draft.trim().is_empty() {
+                    return (Command::none(), None);
+                }
+                // Clients without threads show the message as a reply to the newest one
+                let latest = self
+                    .rooms
+                    .get(&room_id)
+                    .and_then(|room| room.timeline.thread(&root))
+                    .and_then(|thread| thread.replies.last())
+                    .map_or_else(|| root.clone(), |reply| reply.event_id.clone());
+                let (text, html) = markdown::compose(&self.thread_draft);
+                let html = html.filter(|_| self.config.composer.markdown);
+                // The SDK has no thread relation, so the content is built as JSON. It still
+                // encrypts it like any other message
+                let content = AnyMessageEventContent::Custom(CustomEventContent {
+                    event_type: String::from("m.room.message"),
+                    json: thread_content(&root, &latest, text, html),
+                });
+                let client = self.client.clone();
+                return (
+                    Command::perform(
+                        async move { client.room_send(&room_id, content, None).await },
+                        |result| match result {
+                            Ok(_) => Messages::ThreadMessageSent,
+                            Err(e) => Messages::ThreadSendFailed(e.to_string()),
+                        },
+                    ),
+                    None,
+                );
+            }
+            Messages::ThreadMessageSent => {
+                self.thread_draft = String::new();
+                self.thread_error = None;
+            }
+            Messages::ThreadSendFailed(e) => {
+                tracing::warn!("Sending in thread failed: {}", e);
+                self.thread_error = Some(e);
+            }
+            Messages::RevealSpoilers(id) => {
+                self.revealed_spoilers.insert(id);
+            }
+            Messages::ShowReactionPicker(id) => {
+                // Pressing the button again closes the picker
+                self.reacting_to = if self.reacting_to.as_ref() == Some(&id) {
+                    None
+                } else {
+                    Some(id)
+                };
+            }
+            Messages::ToggleReaction(event_id, key) => {
+                self.reacting_to = None;
+                self.reaction_error = None;
+                let room_id = match self.selected.clone() {
+                    Some(room_id) => room_id,
+                    None => return (Command::none(), None),
+                };
+                let own_reaction = self
+                    .rooms
+                    .get(&room_id)
+                    .and_then(|room| {
+                        room.timeline
+                            .own_reaction(&event_id, &self.session.user_id, &key)
+                    })
+                    .cloned();
+                let client = self.client.clone();
+                return (
+                    async move {
+                        let result = match own_reaction {
+                            // Reacting with the same key again takes the reaction back
+                            Some(reaction) => redact(&client, &room_id, &reaction, None).await,
+                            None => react(&client, &room_id, &event_id, &key).await,
+                        };
+                        match result {
+                            Ok(_) => Messages::ReactionToggled(event_id),
+                            Err(e) => Messages::ReactionFailed(event_id, e.to_string()),
+                        }
+                    }
+                    .into(),
+                    None,
+                );
+            }
+            // The sync brings the reaction or its redaction
+            Messages::ReactionToggled(id) => {
+                if self.reaction_error.as_ref().map(|(event, _)| event) == Some(&id) {
+                    self.reaction_error = None;
+                }
+            }
+            Messages::ReactionFailed(id, e) => {
+                tracing::warn!("Reacting failed: {}", e);
+                self.reaction_error = Some((id, e));
+            }
+            Messages::CloseEditHistory => self.edit_history = None,
+            Messages::MembersLoaded(_, id) => {
+                if let Some(room) = self.rooms.get_mut(&id) {
+                    room.members_loaded = true;
+                    // Users missing from the lazy loaded members may be in the full list
+                    room.members.retain(|_, member| member.is_some());
+                }
+                if self.selected.as_ref() == Some(&id) {
+                    return (self.resolve_members(&id), None);
+                }
+            }
+            Messages::SwitchAccount(user_id) => {
+                if user_id != self.session.user_id {
+                    if let Some(account) = self
+                        .accounts
+                        .iter()
+                        .find(|account| account.session.user_id == user_id)
+                        .cloned()
+                    {
+                        return (self.switch_account(account), None);
+                    }
+                }
+            }
+            Messages::AddAccount => {
+                return (
+                    Command::none(),
+                    Some(Elementary::LoginPage(LoginPage::with_accounts(
+                        self.paths.clone(),
+                        self.config.clone(),
+                        self.accounts.clone(),
+                    ))),
+                );
+            }
+            Messages::Logout => {
+                let client = self.client.clone();
+                let session = self.session.clone();
+                return (
+                    Command::perform(logout(self.paths.clone(), client, session), |result| {
+                        match result {
+                            Ok(user_id) => Messages::LoggedOut(user_id),
+                            Err(e) => Messages::LoginFailed(e.to_string()),
+                        }
+                    }),
+                    None,
+                );
+            }
+            Messages::LoggedOut(user_id) => {
+                // Dropping the account from the list also stops its sync subscription
+                self.accounts
+                    .retain(|account| account.session.user_id != user_id);
+                self.sync_status.remove(&user_id);
+                if user_id == self.session.user_id {
+                    match self.accounts.first().cloned() {
+                        Some(account) => return (self.switch_account(account), None),
+                        None => {
+                            return (
+                                Command::none(),
+                                Some(Elementary::LoginPage(LoginPage::new(
+                                    self.paths.clone(),
+                                    self.config.clone(),
+                                ))),
+                            )
+                        }
+                    }
+                }
+            }
+            Messages::LoginResult(client, session) => {
+                // Another stored account finished restoring
+                self.accounts
+                    .retain(|account| account.session.user_id != session.user_id);
+                self.accounts.push(Account { client, session });
+            }
             Messages::SetMessage(message) => self.draft = message,
             Messages::SendMessage => {
                 let selected = match self.selected.clone() {
                     Some(selected) => selected,
-                    None => return Command::none(),
+                    None => return (Command::none(), None),
+                };
+                let original = self.replying_to.take().and_then(|id| {
+                    self.rooms
+                        .get(&selected)
+                        .and_then(|room| room.timeline.replied(&id).cloned())
+                });
+                let (text, html) = markdown::compose(&self.draft);
+                let html = html.filter(|_| self.config.composer.markdown);
+                let content = match (original, html) {
+                    (Some(original), html) => reply_content(&original, text, html),
+                    (None, Some(html)) => MessageEventContent::text_html(text, html),
+                    (None, None) => MessageEventContent::text_plain(text),
                 };
-                let draft = self.draft.clone();
                 let client = self.client.clone();
-                return Command::perform(
-                    async move {
-                        client
-                            .room_send(
-                                &selected,
-                                AnyMessageEventContent::RoomMessage(
-                                    MessageEventContent::text_plain(draft),
-                                ),
-                                None,
-                            )
-                            .await
-                    },
-                    |result| match result {
-                        Ok(_) => Messages::SetMessage(String::new()),
-                        Err(e) => Messages::LoginFailed(e.to_string()),
-                    },
+                return (
+                    Command::perform(
+                        async move {
+                            client
+                                .room_send(
+                                    &selected,
+                                    AnyMessageEventContent::RoomMessage(content),
+                                    None,
+                                )
+                                .await
+                        },
+                        |result| match result {
+                            Ok(_) => Messages::SetMessage(String::new()),
+                            Err(e) => Messages::LoginFailed(e.to_string()),
+                        },
+                    ),
+                    None,
                 );
             }
             _ => {}
         };
 
-        Command::none()
+        (Command::none(), None)
     }
 
     pub fn view(&mut self) -> iced::Element<'_, Messages> {
@@ -325,7 +1121,7 @@ impl HomePage {
         // Sort by Recent
         for list in [&mut dm_rooms, &mut group_rooms].iter_mut() {
             list.sort_unstable_by(|(_, a), (_, b)| {
-                a.messages.updated.cmp(&b.messages.updated).reverse()
+                a.timeline.updated.cmp(&b.timeline.updated).reverse()
             })
         }
 
@@ -388,8 +1184,65 @@ impl HomePage {
             .enumerate()
             .filter_map(|(idx, button)| button_generator(button, idx, &group_rooms));
 
+        // Account switcher
+        self.account_buttons
+            .resize_with(self.accounts.len(), Default::default);
+
+        let mut account_col = Column::new().spacing(5);
+        for (account, button) in self.accounts.iter().zip(self.account_buttons.iter_mut()) {
+            let unread = Self::unread_count(account, &self.config.notifications);
+            let label = if unread > 0 {
+                format!("{} ({})", account.session.user_id, unread)
+            } else {
+                account.session.user_id.to_string()
+            };
+            let mut account_button = Button::new(button, Text::new(label))
+                .width(Length::Fill)
+                .style(theme);
+            if account.session.user_id != self.session.user_id {
+                account_button = account_button
+                    .on_press(Messages::SwitchAccount(account.session.user_id.clone()));
+            }
+            account_col = account_col.push(account_button);
+        }
+        account_col = account_col.push(
+            Button::new(&mut self.add_account_button, Text::new("Add account"))
+                .width(Length::Fill)
+                .style(theme)
+                .on_press(Messages::AddAccount),
+        );
+        account_col = account_col.push(
+            Button::new(&mut self.logout_button, Text::new("Log out"))
+                .width(Length::Fill)
+                .style(theme)
+                .on_press(Messages::Logout),
+        );
+
         let mut room_scroll_view = Scrollable::new(&mut self.room_scroll).spacing(10);
 
+        // Status indicator of the selected account
+        let sync_status = self
+            .sync_status
+            .get(&self.session.user_id)
+            .cloned()
+            .unwrap_or_default();
+        let (status, status_color) = match sync_status {
+            SyncStatus::Connecting => ("Connecting", [0.9, 0.8, 0.3]),
+            SyncStatus::Syncing => ("Online", [0.4, 0.9, 0.4]),
+            SyncStatus::Offline { .. } => ("Offline", [1.0, 0.4, 0.4]),
+            SyncStatus::Error { .. } => ("Error", [1.0, 0.4, 0.4]),
+        };
+        room_scroll_view = room_scroll_view
+            .push(
+                Container::new(
+                    Text::new(format!("● {}", status))
+                        .size(15)
+                        .color(status_color),
+                )
+                .padding(10),
+            )
+            .push(Container::new(Text::new("Accounts")).padding(10))
+            .push(account_col);
         room_scroll_view =
             room_scroll_view.push(Container::new(Text::new("Direct messages")).padding(10));
         for button in dm_buttons.into_iter() {
@@ -402,7 +1255,39 @@ impl HomePage {
 
         let mut message_col = Column::new().spacing(5).padding(5);
 
+        // Connection banner, we keep showing what we have cached while offline
+        let banner = match sync_status {
+            SyncStatus::Offline { retry_in } => Some(format!(
+                "Can't reach the server, retrying in {} seconds...",
+                retry_in.as_secs()
+            )),
+            SyncStatus::Error {
+                ref message,
+                retry_in,
+            } => Some(format!(
+                "Syncing failed, retrying in {} seconds... ({})",
+                retry_in.as_secs(),
+                message
+            )),
+            _ => None,
+        };
+        if let Some(banner) = banner {
+            message_col = message_col.push(
+                Container::new(Text::new(banner).size(15).color([1.0, 0.4, 0.4]))
+                    .width(Length::Fill)
+                    .center_x()
+                    .padding(5),
+            );
+        }
+
+        let mut reply_name = None;
+        let mut thread_panel = None;
         if let Some((room, joined)) = selected_room {
+            reply_name = self
+                .replying_to
+                .as_ref()
+                .and_then(|id| room.timeline.replied(id))
+                .map(|original| get_sender_details(original.sender.clone(), joined.clone()).0);
             // Include user id or canonical alias in title when appropriate
             let title = if let Some(ref direct) = room.direct {
                 format!("{} ({})", &room.name, direct)
@@ -431,9 +1316,9 @@ impl HomePage {
                 .height(Length::Fill);
 
             // Backfill button or loading message
-            let backfill: Element<_> = if room.messages.loading {
+            let backfill: Element<_> = if room.timeline.loading {
                 Text::new("Loading...").into()
-            } else if room.messages.has_beginning() {
+            } else if room.timeline.has_beginning() {
                 let creation = joined.create_content().unwrap();
                 let mut col =
                     Column::new().push(Text::new("This is the beginning of room history"));
@@ -457,57 +1342,409 @@ impl HomePage {
 
             let mut message_content_col = Column::new();
             let mut message_container = Row::new().spacing(5);
-            for message in room.message_list.iter() {
-                let sender = message.sender.clone();
-
-                if last_sender != Some(sender.clone()) {
-                    last_sender = Some(sender.clone());
+            self.gap_buttons
+                .resize_with(room.timeline.chunks.len(), Default::default);
+            self.edit_buttons
+                .resize_with(room.timeline.edited(), Default::default);
+            let mut edit_buttons = self.edit_buttons.iter_mut();
+            self.redact_buttons
+                .resize_with(room.timeline.events().count(), Default::default);
+            let mut redact_buttons = self.redact_buttons.iter_mut();
+            let own_power = room.own_power;
+            let redact_level = room.redact_level();
+            self.react_buttons
+                .resize_with(room.timeline.events().count(), Default::default);
+            let mut react_buttons = self.react_buttons.iter_mut();
+            self.reply_buttons
+                .resize_with(room.timeline.events().count(), Default::default);
+            let mut reply_buttons = self.reply_buttons.iter_mut();
+            self.thread_buttons
+                .resize_with(room.timeline.events().count(), Default::default);
+            let mut thread_buttons = self.thread_buttons.iter_mut();
+            self.link_buttons.resize_with(
+                room.timeline
+                    .events()
+                    .filter_map(|event| room.timeline.body(event.event_id()))
+                    .map(button_count)
+                    .sum(),
+                Default::default,
+            );
+            let mut link_buttons = self.link_buttons.iter_mut();
+            // Links to users and rooms show what they point to
+            let rooms = &self.rooms;
+            let pill = |permalink: &Permalink, url: &str| {
+                let (joined_room, id) = match permalink {
+                    Permalink::User(user_id) => {
+                        return match room.members.get(user_id) {
+                            Some(Some(member)) => Pill {
+                                name: member.name.clone(),
+                                avatar: member
+                                    .avatar
+                                    .as_deref()
+                                    .and_then(|avatar| ims.get(avatar).cloned()),
+                                on_press: Messages::ShowProfile(user_id.clone()),
+                            },
+                            _ => Pill {
+                                name: user_id.to_string(),
+                                avatar: None,
+                                on_press: Messages::ShowProfile(user_id.clone()),
+                            },
+                        };
+                    }
+                    Permalink::Room(room_id, _) => {
+                        (rooms.get_key_value(room_id), room_id.to_string())
+                    }
+                    Permalink::Alias(alias, _) => (
+                        rooms
+                            .iter()
+                            .find(|(_, room)| room.alias.as_ref() == Some(alias)),
+                        alias.to_string(),
+                    ),
+                };
+                match joined_room {
+                    Some((room_id, room)) => Pill {
+                        name: room.name.clone(),
+                        avatar: room
+                            .avatar
+                            .as_deref()
+                            .and_then(|avatar| ims.get(avatar).cloned()),
+                        on_press: Messages::SelectRoom(room_id.clone()),
+                    },
+                    // Rooms we're not in are opened in the browser
+                    None => Pill {
+                        name: id,
+                        avatar: None,
+                        on_press: Messages::OpenLink(url.to_owned()),
+                    },
+                }
+            };
+            let chip_count = room
+                .timeline
+                .events()
+                .map(|event| room.timeline.reactions(event.event_id()).len())
+                .sum();
+            self.reaction_buttons
+                .resize_with(chip_count, Default::default);
+            let mut reaction_buttons = self.reaction_buttons.iter_mut();
+            self.picker_buttons
+                .resize_with(QUICK_REACTIONS.len(), Default::default);
+            // The picker is only open for a single message
+            let mut picker_buttons = Some(self.picker_buttons.iter_mut());
+            let chunks = room.timeline.chunks.iter().enumerate();
+            for ((index, chunk), gap_button) in chunks.zip(self.gap_buttons.iter_mut()) {
+                // Marker for the messages we're missing before this chunk
+                if let Some(gap) = room.timeline.gap(index) {
                     message_container = message_container.push(message_content_col);
-                    scroll = scroll.push(message_container);
+                    scroll = scroll.push(message_container).push(
+                        Container::new(
+                            Button::new(gap_button, Text::new("Load missing messages"))
+                                .style(theme)
+                                .on_press(Messages::FillGap(
+                                    self.selected.clone().unwrap(),
+                                    gap.from,
+                                    gap.direction,
+                                )),
+                        )
+                        .width(Length::Fill)
+                        .center_x(),
+                    );
                     message_content_col = Column::new();
                     message_container = Row::new().spacing(5);
+                    last_sender = None;
+                }
+                for event in chunk.events.iter() {
+                    let (sender, event_id) = match event {
+                        // Edits are shown in place of the message they edit
+                        _ if replaced_event(event).is_some() => continue,
+                        // Messages in threads are shown in the thread panel
+                        _ if room.timeline.thread_root_of(event.event_id()).is_some() => continue,
+                        AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => {
+                            (message.sender.clone(), &message.event_id)
+                        }
+                        AnyRoomEvent::RedactedMessage(AnyRedactedMessageEvent::RoomMessage(
+                            message,
+                        )) => (message.sender.clone(), &message.event_id),
+                        _ => continue,
+                    };
 
-                    let user_details = get_sender_details(sender, joined.clone());
-                    //let mut user_row = Row::new().spacing(5);
-                    match user_details.1 {
-                        Some(image) => match ims.get(&image) {
-                            Some(image_handle) => {
-                                message_container = message_container.push(
-                                    Image::new(image_handle.to_owned())
-                                        .width(20.into())
-                                        .height(20.into()),
-                                );
-                            }
+                    if last_sender != Some(sender.clone()) {
+                        last_sender = Some(sender.clone());
+                        message_container = message_container.push(message_content_col);
+                        scroll = scroll.push(message_container);
+                        message_content_col = Column::new();
+                        message_container = Row::new().spacing(5);
+
+                        let user_details = get_sender_details(sender.clone(), joined.clone());
+                        //let mut user_row = Row::new().spacing(5);
+                        match user_details.1 {
+                            Some(image) => match ims.get(&image) {
+                                Some(image_handle) => {
+                                    message_container = message_container.push(
+                                        Image::new(image_handle.to_owned())
+                                            .width(20.into())
+                                            .height(20.into()),
+                                    );
+                                }
+                                None => {}
+                            },
                             None => {}
-                        },
-                        None => {}
-                    }
+                        }
 
-                    message_content_col = message_content_col.push(Text::new(user_details.0));
-                    //message_container =
-                    //    message_container.push(user_row.push(Text::new(user_details.0)).padding(5));
-                }
-                match message.content.clone() {
-                    MessageEventContent::Audio(_) => {
-                        println!("Audio Message");
+                        message_content_col = message_content_col.push(Text::new(user_details.0));
+                        //message_container =
+                        //    message_container.push(user_row.push(Text::new(user_details.0)).padding(5));
                     }
-                    MessageEventContent::Emote(_) => {}
-                    MessageEventContent::File(_) => {}
-                    MessageEventContent::Image(_) => {}
-                    MessageEventContent::Location(_) => {}
-                    MessageEventContent::Notice(_) => {}
-                    MessageEventContent::ServerNotice(_) => {}
-                    MessageEventContent::Text(text) => {
-                        //message_container = message_container.push(Text::new(&text.body));
-                        message_content_col = message_content_col.push(Text::new(&text.body));
+                    // Removed messages leave a tombstone
+                    if let Some(redaction) = room.timeline.redaction(event_id) {
+                        let mut details = Vec::new();
+                        if let Some(ref redacter) = redaction.sender {
+                            if redacter != &sender {
+                                let name = get_sender_details(redacter.clone(), joined.clone()).0;
+                                details.push(format!("by {}", name));
+                            }
+                        }
+                        if let Some(ref reason) = redaction.reason {
+                            details.push(reason.clone());
+                        }
+                        let text = if details.is_empty() {
+                            String::from("Message deleted")
+                        } else {
+                            format!("Message deleted ({})", details.join(", "))
+                        };
+                        message_content_col =
+                            message_content_col.push(Text::new(text).color([0.5, 0.5, 0.5]));
+                        continue;
                     }
-                    MessageEventContent::Video(_) => {}
-                    MessageEventContent::VerificationRequest(_) => {}
-                    _ => {
-                        println!("Unknown message type");
+                    let message = match event {
+                        AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(message)) => message,
+                        _ => continue,
+                    };
+                    let edited = !room.timeline.edits(message).is_empty();
+                    // Our own messages can always be removed, other people's only by moderators
+                    let can_redact = sender == self.session.user_id || own_power >= redact_level;
+                    match room.timeline.content(message).clone() {
+                        MessageEventContent::Audio(_) => {
+                            println!("Audio Message");
+                        }
+                        MessageEventContent::Emote(_) => {}
+                        MessageEventContent::File(_) => {}
+                        MessageEventContent::Image(_) => {}
+                        MessageEventContent::Location(_) => {}
+                        MessageEventContent::Notice(_) => {}
+                        MessageEventContent::ServerNotice(_) => {}
+                        MessageEventContent::Text(text) => {
+                            // Quote of the message this one replies to
+                            let body = match reply_to(&message.content) {
+                                Some(original_id) => {
+                                    let quote = match room.timeline.replied(original_id) {
+                                        _ if room.timeline.redaction(original_id).is_some() => {
+                                            String::from("Message deleted")
+                                        }
+                                        Some(original) => {
+                                            let name = get_sender_details(
+                                                original.sender.clone(),
+                                                joined.clone(),
+                                            )
+                                            .0;
+                                            let content = room.timeline.content(original);
+                                            let body = match reply_to(&original.content) {
+                                                Some(_) => {
+                                                    strip_reply_fallback(content_body(content))
+                                                }
+                                                None => content_body(content),
+                                            };
+                                            format!(
+                                                "{}: {}",
+                                                name,
+                                                body.lines().next().unwrap_or_default()
+                                            )
+                                        }
+                                        None if room.timeline.is_unavailable(original_id) => {
+                                            String::from("Unable to load message")
+                                        }
+                                        None => String::from("Loading message..."),
+                                    };
+                                    message_content_col = message_content_col.push(
+                                        Container::new(
+                                            Text::new(quote).size(14).color([0.6, 0.6, 0.6]),
+                                        )
+                                        .padding(5),
+                                    );
+                                    strip_reply_fallback(&text.body)
+                                }
+                                None => &text.body,
+                            };
+                            //message_container = message_container.push(Text::new(&text.body));
+                            let body: Element<_> = match room.timeline.body(&message.event_id) {
+                                Some(blocks) => Renderer {
+                                    buttons: &mut link_buttons,
+                                    theme,
+                                    revealed: self.revealed_spoilers.contains(&message.event_id),
+                                    reveal: Messages::RevealSpoilers(message.event_id.clone()),
+                                    pill: &pill,
+                                }
+                                .blocks(blocks)
+                                .into(),
+                                None => Text::new(body).into(),
+                            };
+                            let mut row =
+                                Row::new().spacing(5).align_items(Align::Center).push(body);
+                            let edit_button = if edited { edit_buttons.next() } else { None };
+                            if let Some(state) = edit_button {
+                                row = row.push(
+                                    Button::new(
+                                        state,
+                                        Text::new("(edited)").size(12).color([0.6, 0.6, 0.6]),
+                                    )
+                                    .style(theme)
+                                    .on_press(Messages::ShowEditHistory(message.event_id.clone())),
+                                );
+                            }
+                            let redact_button = if can_redact {
+                                redact_buttons.next()
+                            } else {
+                                None
+                            };
+                            if let Some(state) = redact_button {
+                                row = row.push(
+                                    Button::new(
+                                        state,
+                                        Text::new("Delete").size(12).color([0.6, 0.6, 0.6]),
+                                    )
+                                    .style(theme)
+                                    .on_press(Messages::StartRedaction(message.event_id.clone())),
+                                );
+                            }
+                            if let Some(state) = reply_buttons.next() {
+                                row = row.push(
+                                    Button::new(
+                                        state,
+                                        Text::new("Reply").size(12).color([0.6, 0.6, 0.6]),
+                                    )
+                                    .style(theme)
+                                    .on_press(Messages::Reply(message.event_id.clone())),
+                                );
+                            }
+                            if let Some(state) = react_buttons.next() {
+                                row = row.push(
+                                    Button::new(
+                                        state,
+                                        Text::new("React").size(12).color([0.6, 0.6, 0.6]),
+                                    )
+                                    .style(theme)
+                                    .on_press(
+                                        Messages::ShowReactionPicker(message.event_id.clone()),
+                                    ),
+                                );
+                            }
+                            message_content_col = message_content_col.push(row);
+
+                            let reactions = room.timeline.reactions(&message.event_id);
+                            if !reactions.is_empty() {
+                                let mut chips = Row::new().spacing(5);
+                                for ((key, senders), state) in
+                                    reactions.into_iter().zip(&mut reaction_buttons)
+                                {
+                                    let names: Vec<String> = senders
+                                        .into_iter()
+                                        .map(|sender| {
+                                            get_sender_details(sender.clone(), joined.clone()).0
+                                        })
+                                        .collect();
+                                    let chip = Button::new(
+                                        state,
+                                        Text::new(format!("{} {}", key, names.len()))
+                                            .size(14)
+                                            .font(ICONS),
+                                    )
+                                    .style(theme)
+                                    .on_press(
+                                        Messages::ToggleReaction(
+                                            message.event_id.clone(),
+                                            key.to_owned(),
+                                        ),
+                                    );
+                                    chips = chips.push(
+                                        Tooltip::new(
+                                            chip,
+                                            &names.join(", "),
+                                            tooltip::Position::Top,
+                                        )
+                                        .style(theme),
+                                    );
+                                }
+                                message_content_col = message_content_col.push(chips);
+                            }
+                            if let Some((_, error)) = self
+                                .reaction_error
+                                .as_ref()
+                                .filter(|(event, _)| event == &message.event_id)
+                            {
+                                message_content_col = message_content_col.push(
+                                    Text::new(format!("Reacting failed: {}", error))
+                                        .size(12)
+                                        .color([1.0, 0.4, 0.4]),
+                                );
+                            }
+
+                            if self.reacting_to.as_ref() == Some(&message.event_id) {
+                                if let Some(buttons) = picker_buttons.take() {
+                                    let mut picker = Row::new().spacing(5);
+                                    for (key, state) in QUICK_REACTIONS.iter().zip(buttons) {
+                                        picker = picker.push(
+                                            Button::new(state, Text::new(*key).font(ICONS))
+                                                .style(theme)
+                                                .on_press(Messages::ToggleReaction(
+                                                    message.event_id.clone(),
+                                                    (*key).to_owned(),
+                                                )),
+                                        );
+                                    }
+                                    message_content_col = message_content_col.push(picker);
+                                }
+                            }
+
+                            // Summary of the thread started from the message
+                            let latest =
+                                room.timeline.thread(&message.event_id).and_then(|thread| {
+                                    Some((thread.replies.len(), thread.replies.last()?))
+                                });
+                            if let (Some((count, latest)), Some(state)) =
+                                (latest, thread_buttons.next())
+                            {
+                                let name =
+                                    get_sender_details(latest.sender.clone(), joined.clone()).0;
+                                let content = room.timeline.content(latest);
+                                let preview = strip_reply_fallback(content_body(content))
+                                    .lines()
+                                    .next()
+                                    .unwrap_or_default();
+                                let count = match count {
+                                    1 => String::from("1 reply"),
+                                    count => format!("{} replies", count),
+                                };
+                                message_content_col = message_content_col.push(
+                                    Button::new(
+                                        state,
+                                        Text::new(format!("{}, {}: {}", count, name, preview))
+                                            .size(14)
+                                            .color([0.6, 0.6, 0.6]),
+                                    )
+                                    .style(theme)
+                                    .on_press(Messages::OpenThread(message.event_id.clone())),
+                                );
+                            }
+                        }
+                        MessageEventContent::Video(_) => {}
+                        MessageEventContent::VerificationRequest(_) => {}
+                        _ => {
+                            println!("Unknown message type");
+                        }
                     }
                 }
             }
+            // The last sender's messages
+            scroll = scroll.push(message_container.push(message_content_col));
 
             //for event in room.messages.messages.iter() {
             //    #[allow(clippy::single_match)]
@@ -632,6 +1869,191 @@ impl HomePage {
                 );
             }
             message_col = message_col.push(scroll);
+
+            // Edit history of a message
+            if let Some(message) = self
+                .edit_history
+                .as_ref()
+                .and_then(|id| room.timeline.message(id))
+            {
+                let mut history = Column::new()
+                    .spacing(5)
+                    .padding(10)
+                    .push(Text::new("Edit history").size(20));
+                for (time, content) in room.timeline.versions(message) {
+                    history = history.push(
+                        Row::new()
+                            .spacing(10)
+                            .push(
+                                Text::new(format_systime(time))
+                                    .size(15)
+                                    .color([0.6, 0.6, 0.6]),
+                            )
+                            .push(Text::new(content_body(content))),
+                    );
+                }
+                history = history.push(
+                    Button::new(&mut self.close_history_button, Text::new("Close"))
+                        .style(theme)
+                        .on_press(Messages::CloseEditHistory),
+                );
+                message_col = message_col
+                    .push(Rule::horizontal(2))
+                    .push(Container::new(history).width(Length::Fill));
+            }
+
+            // Profile of a user a message links to
+            if let Some(ref user_id) = self.profile {
+                let member = room.members.get(user_id);
+                let mut details = Row::new().spacing(10).align_items(Align::Center);
+                if let Some(handle) = member
+                    .and_then(Option::as_ref)
+                    .and_then(|member| member.avatar.as_deref())
+                    .and_then(|url| ims.get(url))
+                {
+                    details = details.push(
+                        Image::new(handle.to_owned())
+                            .width(48.into())
+                            .height(48.into()),
+                    );
+                }
+                let mut names = Column::new().spacing(5);
+                if let Some(Some(member)) = member {
+                    names = names.push(Text::new(&member.name).size(20));
+                }
+                names = names.push(Text::new(user_id.as_str()).size(15).color([0.6, 0.6, 0.6]));
+                if let Some(None) = member {
+                    names = names.push(Text::new("Not a member of this room").size(15));
+                }
+                let profile = Column::new()
+                    .spacing(5)
+                    .padding(10)
+                    .push(details.push(names))
+                    .push(
+                        Button::new(&mut self.close_profile_button, Text::new("Close"))
+                            .style(theme)
+                            .on_press(Messages::CloseProfile),
+                    );
+                message_col = message_col
+                    .push(Rule::horizontal(2))
+                    .push(Container::new(profile).width(Length::Fill));
+            }
+
+            // Confirmation before removing a message
+            if self.redacting.is_some() {
+                let mut confirmation = Column::new()
+                    .spacing(5)
+                    .padding(10)
+                    .push(Text::new("Delete this message?").size(20))
+                    .push(
+                        TextInput::new(
+                            &mut self.redact_reason_input,
+                            "Reason (optional)",
+                            &self.redact_reason,
+                            Messages::RedactReasonChanged,
+                        )
+                        .padding(5)
+                        .style(theme)
+                        .on_submit(Messages::Redact),
+                    )
+                    .push(
+                        Row::new()
+                            .spacing(5)
+                            .push(
+                                Button::new(&mut self.confirm_redact_button, Text::new("Delete"))
+                                    .style(theme)
+                                    .on_press(Messages::Redact),
+                            )
+                            .push(
+                                Button::new(&mut self.cancel_redact_button, Text::new("Cancel"))
+                                    .style(theme)
+                                    .on_press(Messages::CancelRedaction),
+                            ),
+                    );
+                if let Some(ref error) = self.redact_error {
+                    confirmation = confirmation.push(Text::new(error).color([1.0, 0.4, 0.4]));
+                }
+                message_col = message_col
+                    .push(Rule::horizontal(2))
+                    .push(Container::new(confirmation).width(Length::Fill));
+            }
+
+            // Side panel with the messages of a thread
+            if let Some(ref root) = self.thread {
+                let entry = |message: &MessageEvent<MessageEventContent>| {
+                    let name = get_sender_details(message.sender.clone(), joined.clone()).0;
+                    let body = strip_reply_fallback(content_body(room.timeline.content(message)));
+                    Column::new()
+                        .spacing(2)
+                        .push(Text::new(name).size(14).color([0.6, 0.6, 0.6]))
+                        .push(Text::new(body))
+                };
+                let mut replies = Scrollable::new(&mut self.thread_scroll)
+                    .scrollbar_width(2)
+                    .spacing(10)
+                    .height(Length::Fill);
+                if let Some(original) = room.timeline.replied(root) {
+                    replies = replies.push(entry(original)).push(Rule::horizontal(2));
+                }
+                let thread = room.timeline.thread(root);
+                if thread.map_or(false, |thread| thread.loading) {
+                    replies = replies.push(Text::new("Loading...").size(14));
+                } else if thread.map_or(false, |thread| thread.loaded && thread.has_more()) {
+                    replies = replies.push(
+                        Button::new(
+                            &mut self.thread_more_button,
+                            Text::new("Load older replies"),
+                        )
+                        .style(theme)
+                        .on_press(Messages::LoadThread),
+                    );
+                }
+                for reply in thread.into_iter().flat_map(|thread| thread.replies.iter()) {
+                    replies = replies.push(entry(reply));
+                }
+                let mut panel = Column::new()
+                    .spacing(5)
+                    .padding(5)
+                    .push(
+                        Row::new()
+                            .padding(6)
+                            .spacing(10)
+                            .align_items(Align::Center)
+                            .push(Text::new("Thread").size(25).width(Length::Fill))
+                            .push(
+                                Button::new(&mut self.close_thread_button, Text::new("Close"))
+                                    .style(theme)
+                                    .on_press(Messages::CloseThread),
+                            ),
+                    )
+                    .push(Rule::horizontal(2))
+                    .push(replies);
+                if let Some(ref error) = self.thread_error {
+                    panel = panel.push(Text::new(error).size(14).color([1.0, 0.4, 0.4]));
+                }
+                panel = panel.push(
+                    Row::new()
+                        .spacing(5)
+                        .push(
+                            TextInput::new(
+                                &mut self.thread_input,
+                                "Reply in thread...",
+                                &self.thread_draft,
+                                Messages::SetThreadMessage,
+                            )
+                            .width(Length::Fill)
+                            .padding(5)
+                            .style(theme)
+                            .on_submit(Messages::SendThreadMessage),
+                        )
+                        .push(
+                            Button::new(&mut self.thread_send_button, Text::new("Send"))
+                                .style(theme)
+                                .on_press(Messages::SendThreadMessage),
+                        ),
+                );
+                thread_panel = Some(panel);
+            }
         } else {
             message_col = message_col.push(
                 Container::new(Text::new("Select a room to start chatting"))
@@ -642,6 +2064,19 @@ impl HomePage {
             );
         }
 
+        if let Some(name) = reply_name {
+            message_col = message_col.push(
+                Row::new()
+                    .spacing(5)
+                    .align_items(Align::Center)
+                    .push(Text::new(format!("Replying to {}", name)).size(14))
+                    .push(
+                        Button::new(&mut self.cancel_reply_button, Text::new("Cancel").size(14))
+                            .style(theme)
+                            .on_press(Messages::CancelReply),
+                    ),
+            );
+        }
         message_col = message_col.push(
             Row::new()
                 .spacing(5)
@@ -674,7 +2109,16 @@ impl HomePage {
             .width(Length::FillPortion(4))
             .style(Theme::DarkRoom);
 
-        Container::new(Row::new().push(room_list_view).push(message_view))
+        let mut panels = Row::new().push(room_list_view).push(message_view);
+        if let Some(thread_panel) = thread_panel {
+            panels = panels.push(
+                Container::new(thread_panel)
+                    .height(Length::Fill)
+                    .width(Length::FillPortion(2))
+                    .style(Theme::DarkRoom),
+            );
+        }
+        Container::new(panels)
             .height(Length::Fill)
             .style(self.theme)
             .into()
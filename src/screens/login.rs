@@ -1,12 +1,14 @@
 use std::convert::TryInto;
 
-use crate::{database::connection::establish_connection, matrix::room::RoomEntry};
+use crate::{config::Config, paths::Paths};
 
-use super::{elementary::Elementary, HomePage, LoginPage, Messages, VerifyPage};
+use super::{
+    elementary::Elementary, Account, HomePage, LoginPage, Messages, RegisterPage, VerifyPage,
+};
 use iced::{Button, Column, Command, Container, Length, Row, Svg, Text, TextInput};
 use num_traits::FromPrimitive;
 
-#[derive(FromPrimitive)]
+#[derive(FromPrimitive, Clone, Copy, Debug, PartialEq)]
 pub enum TextBoxes {
     Homeserver = 0,
     Username,
@@ -14,6 +16,26 @@ pub enum TextBoxes {
 }
 
 impl LoginPage {
+    pub fn new(paths: Paths, config: Config) -> Self {
+        LoginPage {
+            theme: config.appearance.theme,
+            paths,
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Login page for adding another account while staying logged into `accounts`
+    pub fn with_accounts(paths: Paths, config: Config, accounts: Vec<Account>) -> Self {
+        LoginPage {
+            theme: config.appearance.theme,
+            paths,
+            config,
+            accounts,
+            ..Default::default()
+        }
+    }
+
     pub fn set_focus(&mut self, to_focus: TextBoxes) {
         self.homerserver_state.unfocus();
         self.username_state.unfocus();
@@ -25,7 +47,37 @@ impl LoginPage {
         }
     }
 
+    /// The text box that has the focus
+    fn focused_field(&self) -> Option<TextBoxes> {
+        [
+            &self.homerserver_state,
+            &self.username_state,
+            &self.password_state,
+        ]
+        .iter()
+        .position(|textbox| textbox.is_focused())
+        .and_then(FromPrimitive::from_usize)
+    }
+
     pub fn update(&mut self, message: Messages) -> (Command<Messages>, Option<Elementary>) {
+        let (command, page) = self.handle(message);
+        if page.is_some() {
+            return (command, page);
+        }
+        // Leaving the homeserver or username field means we can look up how to log in
+        let focused = self.focused_field();
+        let left = self.focused.filter(|&field| Some(field) != focused);
+        self.focused = focused;
+        match left {
+            Some(TextBoxes::Homeserver) | Some(TextBoxes::Username) => {
+                let (query, _) = self.handle(Messages::QueryLoginFlows);
+                (Command::batch(vec![command, query]), None)
+            }
+            _ => (command, None),
+        }
+    }
+
+    fn handle(&mut self, message: Messages) -> (Command<Messages>, Option<Elementary>) {
         let textboxes = [
             &self.homerserver_state,
             &self.username_state,
@@ -33,8 +85,17 @@ impl LoginPage {
         ];
 
         match message {
-            Messages::HomeserverChanged(input) => self.homeserver_url = input,
-            Messages::UsernameChanged(input) => self.username = input,
+            Messages::HomeserverChanged(input) => {
+                self.homeserver_url = input;
+                self.flows = None;
+            }
+            Messages::UsernameChanged(input) => {
+                self.username = input;
+                // The homeserver is looked up from the user id when none was entered
+                if self.homeserver_url.trim().is_empty() {
+                    self.flows = None;
+                }
+            }
             Messages::PasswordChanged(input) => self.password = input,
             Messages::FocusNext => {
                 let focus_index = textboxes.iter().position(|textbox| textbox.is_focused());
@@ -61,13 +122,29 @@ impl LoginPage {
                 }
             }
             Messages::Submit => {
+                if self.username.trim().is_empty() || self.password.is_empty() {
+                    self.error = Some(String::from("Enter your username and password"));
+                    return (Command::none(), None);
+                }
+                self.error = None;
                 let homeser = self.homeserver_url.clone();
                 let user = self.username.clone();
                 let pass = self.password.clone();
+                let paths = self.paths.clone();
+                let device_name = self.config.device.display_name.clone();
 
                 return (
                     Command::perform(
-                        async move { crate::matrix::login::login(&homeser, &user, &pass).await },
+                        async move {
+                            crate::matrix::login::login(
+                                &paths,
+                                &homeser,
+                                &user,
+                                &pass,
+                                &device_name,
+                            )
+                            .await
+                        },
                         |result| match result {
                             Ok((client, session)) => Messages::Verification(client, session.into()),
                             Err(e) => Messages::LoginFailed(e.to_string()),
@@ -76,34 +153,115 @@ impl LoginPage {
                     None,
                 );
             }
-            Messages::LoginResult(client, session) => {
-                println!("Logged In, {:#?}", client);
-                let mut commands: Vec<Command<Messages>> = Vec::new();
-                for room in client.joined_rooms().into_iter() {
-                    let room = std::sync::Arc::new(room);
-                    let r = room.clone();
-                    let command: Command<_> = async move {
-                        let entry = RoomEntry::from_sdk(&r).await;
-                        Messages::ResetRoom(r.room_id().to_owned(), entry)
-                    }
-                    .into();
-                    if let Some(url) = room.avatar_url() {
-                        commands.push(async { Messages::FetchImage(url) }.into())
-                    }
-                    commands.push(command);
+            Messages::QueryLoginFlows => {
+                // A full user id is enough to find the homeserver
+                let unknown =
+                    self.homeserver_url.trim().is_empty() && !self.username.trim().starts_with('@');
+                if unknown || self.flows.is_some() {
+                    return (Command::none(), None);
+                }
+                let homeserver = self.homeserver_url.clone();
+                let user = self.username.clone();
+                return (
+                    Command::perform(
+                        async move {
+                            let flows = crate::matrix::login::login_flows(&homeserver, &user).await;
+                            (homeserver, user, flows)
+                        },
+                        |(homeserver, user, result)| match result {
+                            Ok(flows) => Messages::LoginFlows(homeserver, user, flows),
+                            Err(e) => Messages::LoginFailed(e.to_string()),
+                        },
+                    ),
+                    None,
+                );
+            }
+            Messages::LoginFlows(homeserver, user, flows) => {
+                // Ignore answers for something the user already changed
+                let current = homeserver == self.homeserver_url
+                    && (!homeserver.trim().is_empty() || user == self.username);
+                if current {
+                    self.error = None;
+                    self.flows = Some(flows);
                 }
+            }
+            Messages::SsoLogin => {
+                let flows = match self.flows {
+                    Some(ref flows) if flows.sso => flows.clone(),
+                    _ => return (Command::none(), None),
+                };
+                self.error = None;
+                let paths = self.paths.clone();
+                let device_name = self.config.device.display_name.clone();
+
+                return (
+                    Command::perform(
+                        async move {
+                            crate::matrix::login::login_with_sso(
+                                &paths,
+                                flows.homeserver,
+                                &device_name,
+                            )
+                            .await
+                        },
+                        |result| match result {
+                            Ok((client, session)) => Messages::Verification(client, session.into()),
+                            Err(e) => Messages::LoginFailed(e.to_string()),
+                        },
+                    ),
+                    None,
+                );
+            }
+            Messages::GoRegister => {
                 return (
-                    Command::batch(commands),
-                    Some(Elementary::HomePage(HomePage::new(client, session))),
+                    Command::none(),
+                    Some(Elementary::RegisterPage(RegisterPage::new(
+                        self.paths.clone(),
+                        self.config.clone(),
+                        self.homeserver_url.clone(),
+                        self.accounts.clone(),
+                    ))),
                 );
             }
+            Messages::LoginResult(client, session) => {
+                let home = HomePage::new(
+                    self.paths.clone(),
+                    self.config.clone(),
+                    self.accounts.clone(),
+                    client,
+                    session,
+                );
+                match home {
+                    Ok(home) => return (home.load_rooms(), Some(Elementary::HomePage(home))),
+                    Err(e) => self.error = Some(format!("{:#}", e)),
+                }
+            }
+            Messages::GoHome => {
+                // Abort adding another account
+                if let Some(account) = self.accounts.pop() {
+                    let home = HomePage::new(
+                        self.paths.clone(),
+                        self.config.clone(),
+                        self.accounts.clone(),
+                        account.client,
+                        account.session,
+                    );
+                    match home {
+                        Ok(home) => return (home.load_rooms(), Some(Elementary::HomePage(home))),
+                        Err(e) => self.error = Some(format!("{:#}", e)),
+                    }
+                }
+            }
             Messages::Verification(client, session) => {
                 return (
                     Command::none(),
                     Some(Elementary::VerifyPage(VerifyPage {
-                        theme: Default::default(),
+                        theme: self.theme,
+                        paths: self.paths.clone(),
+                        config: self.config.clone(),
                         client,
                         session,
+                        accounts: self.accounts.clone(),
                         verification_emoji: Default::default(),
                         sas: None,
                         accept_button_state: Default::default(),
@@ -111,7 +269,10 @@ impl LoginPage {
                     })),
                 );
             }
-            Messages::LoginFailed(e) => println!("Login Failed, {:#?}", e),
+            Messages::LoginFailed(e) => {
+                tracing::warn!("Login failed: {}", e);
+                self.error = Some(e);
+            }
             _ => (),
         };
 
@@ -132,32 +293,41 @@ impl LoginPage {
             .width(Length::FillPortion(1))
             .style(self.theme);
 
-        let login_form = Container::new(
-            Column::new()
-                .padding(70)
-                .spacing(20)
-                .push(
-                    TextInput::new(
-                        &mut self.homerserver_state,
-                        "Enter Homeserver URL...",
-                        &self.homeserver_url,
-                        Messages::HomeserverChanged,
-                    )
-                    .size(15)
-                    .padding(12)
-                    .style(self.theme),
+        let mut form = Column::new()
+            .padding(70)
+            .spacing(20)
+            .push(
+                TextInput::new(
+                    &mut self.homerserver_state,
+                    "Enter Homeserver (e.g. example.org)...",
+                    &self.homeserver_url,
+                    Messages::HomeserverChanged,
                 )
-                .push(
-                    TextInput::new(
-                        &mut self.username_state,
-                        "Enter Username...",
-                        &self.username,
-                        Messages::UsernameChanged,
-                    )
-                    .size(15)
-                    .padding(12)
-                    .style(self.theme),
+                .on_submit(Messages::QueryLoginFlows)
+                .size(15)
+                .padding(12)
+                .style(self.theme),
+            )
+            .push(
+                TextInput::new(
+                    &mut self.username_state,
+                    "Enter Username or @user:example.org...",
+                    &self.username,
+                    Messages::UsernameChanged,
                 )
+                .size(15)
+                .padding(12)
+                .style(self.theme),
+            );
+
+        // Only offer what the homeserver supports, once we know what that is
+        let (password, sso) = match self.flows {
+            Some(ref flows) => (flows.password, flows.sso),
+            None => (true, false),
+        };
+
+        if password {
+            form = form
                 .push(
                     TextInput::new(
                         &mut self.password_state,
@@ -178,12 +348,53 @@ impl LoginPage {
                     .width(Length::Fill)
                     .style(self.theme)
                     .on_press(Messages::Submit),
-                ),
-        )
-        .height(Length::Fill)
-        .width(Length::FillPortion(1))
-        .style(self.theme)
-        .center_y();
+                );
+        }
+
+        if sso {
+            form = form.push(
+                Button::new(
+                    &mut self.sso_button_state,
+                    Text::new("SSO").horizontal_alignment(iced::HorizontalAlignment::Center),
+                )
+                .width(Length::Fill)
+                .style(self.theme)
+                .on_press(Messages::SsoLogin),
+            );
+        }
+
+        form = form.push(
+            Button::new(
+                &mut self.register_button_state,
+                Text::new("Create account").horizontal_alignment(iced::HorizontalAlignment::Center),
+            )
+            .width(Length::Fill)
+            .style(self.theme)
+            .on_press(Messages::GoRegister),
+        );
+
+        if let Some(ref error) = self.error {
+            form = form.push(Text::new(error).size(15).color([1.0, 0.4, 0.4]));
+        }
+
+        // Allow going back when we're adding another account
+        if !self.accounts.is_empty() {
+            form = form.push(
+                Button::new(
+                    &mut self.back_button_state,
+                    Text::new("Back").horizontal_alignment(iced::HorizontalAlignment::Center),
+                )
+                .width(Length::Fill)
+                .style(self.theme)
+                .on_press(Messages::GoHome),
+            );
+        }
+
+        let login_form = Container::new(form)
+            .height(Length::Fill)
+            .width(Length::FillPortion(1))
+            .style(self.theme)
+            .center_y();
 
         Container::new(Row::new().push(matrix_logo).push(login_form))
             .height(Length::Fill)
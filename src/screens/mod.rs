@@ -1,25 +1,50 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use diesel::SqliteConnection;
 use iced::{button, text_input};
-use matrix_sdk::{events::AnyMessageEvent, identifiers::RoomId, Client, Sas, Session};
+use matrix_sdk::{
+    events::{room::power_levels::PowerLevelsEventContent, AnyMessageEvent, AnyRoomEvent},
+    identifiers::{EventId, RoomId, UserId},
+    Client, Sas, Session,
+};
 
 use crate::{
+    config::Config,
     database::connection::establish_connection,
-    matrix::{room::RoomEntry, subscriber::MatrixEvents},
+    matrix::{
+        login::LoginFlows,
+        message::Direction,
+        register::RegistrationInfo,
+        room::{Member, RoomEntry},
+        subscriber::{MatrixEvents, SyncStatus},
+    },
+    paths::Paths,
     theme::style,
 };
 
 use matrix_sdk::api::r0::message::get_message_events::Response as MessageResponse;
 
+use login::TextBoxes;
+
 pub mod elementary;
+pub mod formatted;
 pub mod home;
 pub mod login;
+pub mod register;
 pub mod verify;
 
+/// An account we're logged into
+#[derive(Clone)]
+pub struct Account {
+    client: Client,
+    session: Session,
+}
+
 #[derive(Default)]
 pub struct LoginPage {
     theme: style::Theme,
+    paths: Paths,
+    config: Config,
     homerserver_state: text_input::State,
     homeserver_url: String,
     username_state: text_input::State,
@@ -27,12 +52,53 @@ pub struct LoginPage {
     password_state: text_input::State,
     password: String,
     button_state: button::State,
+    /// Login flows of the entered homeserver, once we've asked for them
+    flows: Option<LoginFlows>,
+    /// Text box that had the focus after the last message, to notice when it is left
+    focused: Option<TextBoxes>,
+    sso_button_state: button::State,
+    /// Why the last login attempt failed
+    error: Option<String>,
+    /// Accounts we're already logged into when adding another one
+    accounts: Vec<Account>,
+    back_button_state: button::State,
+    register_button_state: button::State,
+}
+
+#[derive(Default)]
+pub struct RegisterPage {
+    theme: style::Theme,
+    paths: Paths,
+    config: Config,
+    homeserver_state: text_input::State,
+    homeserver_url: String,
+    username_state: text_input::State,
+    username: String,
+    password_state: text_input::State,
+    password: String,
+    confirm_state: text_input::State,
+    confirm_password: String,
+    token_state: text_input::State,
+    token: String,
+    accepted_terms: bool,
+    /// Auth stages of the entered homeserver, once we've asked for them
+    info: Option<RegistrationInfo>,
+    check_button_state: button::State,
+    register_button_state: button::State,
+    back_button_state: button::State,
+    /// Why the last attempt failed
+    error: Option<String>,
+    /// Accounts we're already logged into when adding another one
+    accounts: Vec<Account>,
 }
 
 pub struct VerifyPage {
     theme: style::Theme,
+    paths: Paths,
+    config: Config,
     client: Client,
     session: Session,
+    accounts: Vec<Account>,
     verification_emoji: Vec<(&'static str, &'static str)>,
     sas: Option<Sas>,
     accept_button_state: button::State,
@@ -41,14 +107,70 @@ pub struct VerifyPage {
 
 pub struct HomePage {
     theme: style::Theme,
+    paths: Paths,
+    config: Config,
     conn: SqliteConnection,
+    /// Client of the currently selected account
     client: Client,
     session: Session,
+    /// Every account we're logged into, including the selected one
+    accounts: Vec<Account>,
+    account_buttons: Vec<iced::button::State>,
+    add_account_button: iced::button::State,
+    logout_button: iced::button::State,
     rooms: BTreeMap<RoomId, RoomEntry>,
     selected: Option<RoomId>,
     sync_token: String,
     images: BTreeMap<String, iced::image::Handle>,
+    /// Connection state of every account
+    sync_status: HashMap<UserId, SyncStatus>,
     dm_buttons: Vec<iced::button::State>,
+    /// Buttons to load the messages missing in the selected room
+    gap_buttons: Vec<iced::button::State>,
+    /// Buttons opening the edit history of the edited messages in the selected room
+    edit_buttons: Vec<iced::button::State>,
+    /// Message whose edit history is shown
+    edit_history: Option<EventId>,
+    close_history_button: iced::button::State,
+    redact_buttons: Vec<iced::button::State>,
+    /// Message waiting for the user to confirm its removal
+    redacting: Option<EventId>,
+    redact_reason: String,
+    redact_reason_input: iced::text_input::State,
+    /// Why removing the message in the confirmation dialog failed
+    redact_error: Option<String>,
+    confirm_redact_button: iced::button::State,
+    cancel_redact_button: iced::button::State,
+    react_buttons: Vec<iced::button::State>,
+    reaction_buttons: Vec<iced::button::State>,
+    picker_buttons: Vec<iced::button::State>,
+    /// Message the quick reaction picker is open for
+    reacting_to: Option<EventId>,
+    /// Message whose reaction failed to be sent or taken back, and why
+    reaction_error: Option<(EventId, String)>,
+    reply_buttons: Vec<iced::button::State>,
+    /// Message the draft is a reply to
+    replying_to: Option<EventId>,
+    cancel_reply_button: iced::button::State,
+    link_buttons: Vec<iced::button::State>,
+    /// Messages whose spoilers the user asked to see
+    revealed_spoilers: HashSet<EventId>,
+    /// User whose profile is shown
+    profile: Option<UserId>,
+    close_profile_button: iced::button::State,
+    /// Buttons opening the thread of a message
+    thread_buttons: Vec<iced::button::State>,
+    /// Message whose thread is shown in the side panel
+    thread: Option<EventId>,
+    thread_scroll: iced::scrollable::State,
+    thread_more_button: iced::button::State,
+    close_thread_button: iced::button::State,
+    thread_input: iced::text_input::State,
+    /// Draft of a message in the open thread
+    thread_draft: String,
+    thread_send_button: iced::button::State,
+    /// Why loading or sending in the open thread failed
+    thread_error: Option<String>,
     group_buttons: Vec<iced::button::State>,
     room_scroll: iced::scrollable::State,
     message_scroll: iced::scrollable::State,
@@ -60,17 +182,67 @@ pub struct HomePage {
 }
 
 impl HomePage {
-    fn new(client: Client, session: Session) -> Self {
-        HomePage {
+    fn new(
+        paths: Paths,
+        config: Config,
+        mut accounts: Vec<Account>,
+        client: Client,
+        session: Session,
+    ) -> Result<Self, anyhow::Error> {
+        accounts.retain(|account| account.session.user_id != session.user_id);
+        accounts.push(Account {
+            client: client.clone(),
+            session: session.clone(),
+        });
+        Ok(HomePage {
+            conn: establish_connection(&paths)?,
+            theme: config.appearance.theme,
+            paths,
+            config,
             client,
             session,
-            conn: establish_connection(),
+            accounts,
+            account_buttons: Default::default(),
+            add_account_button: Default::default(),
+            logout_button: Default::default(),
             rooms: Default::default(),
             selected: None,
             sync_token: Default::default(),
             images: Default::default(),
-            theme: Default::default(),
+            sync_status: Default::default(),
             dm_buttons: Default::default(),
+            gap_buttons: Default::default(),
+            edit_buttons: Default::default(),
+            edit_history: None,
+            close_history_button: Default::default(),
+            redact_buttons: Default::default(),
+            redacting: None,
+            redact_reason: String::new(),
+            redact_reason_input: Default::default(),
+            redact_error: None,
+            confirm_redact_button: Default::default(),
+            cancel_redact_button: Default::default(),
+            react_buttons: Default::default(),
+            reaction_buttons: Default::default(),
+            picker_buttons: Default::default(),
+            reacting_to: None,
+            reaction_error: None,
+            reply_buttons: Default::default(),
+            replying_to: None,
+            cancel_reply_button: Default::default(),
+            link_buttons: Default::default(),
+            revealed_spoilers: Default::default(),
+            profile: None,
+            close_profile_button: Default::default(),
+            thread_buttons: Default::default(),
+            thread: None,
+            thread_scroll: Default::default(),
+            thread_more_button: Default::default(),
+            close_thread_button: Default::default(),
+            thread_input: Default::default(),
+            thread_draft: String::new(),
+            thread_send_button: Default::default(),
+            thread_error: None,
             group_buttons: Default::default(),
             room_scroll: Default::default(),
             message_scroll: Default::default(),
@@ -79,7 +251,7 @@ impl HomePage {
             message_input: Default::default(),
             draft: Default::default(),
             send_button: Default::default(),
-        }
+        })
     }
 }
 
@@ -90,17 +262,73 @@ pub enum Messages {
     PasswordChanged(String),
     FocusNext,
     FocusPrev,
+    /// The pointer was pressed, which may have moved the focus
+    CheckFocus,
     Submit,
+    QueryLoginFlows,
+    LoginFlows(String, String, LoginFlows),
+    SsoLogin,
+    GoRegister,
+    ConfirmPasswordChanged(String),
+    RegistrationTokenChanged(String),
+    TermsAccepted(bool),
+    QueryRegistration,
+    RegistrationFlows(String, RegistrationInfo),
+    Register,
     LoginResult(Client, Session),
     LoginFailed(String),
-    Sync(MatrixEvents),
+    Sync(UserId, MatrixEvents),
     FetchImage(String),
     FetchedImage(String, iced::image::Handle),
-    RoomName(RoomId, String),
-    ResetRoom(RoomId, RoomEntry),
+    RoomName(UserId, RoomId, String),
+    ResetRoom(UserId, RoomId, RoomEntry),
     BackFill(RoomId),
-    BackFilled(RoomId, MessageResponse),
+    /// Token to paginate back from the oldest cached event of a room
+    PrevBatchFound(UserId, RoomId, EventId, String),
+    BackFilled(UserId, RoomId, String, Direction, MessageResponse),
+    BackFillFailed(UserId, RoomId, String),
+    FillGap(RoomId, String, Direction),
     SelectRoom(RoomId),
+    MembersLoaded(UserId, RoomId),
+    /// Power levels of a room from the server, `None` if loading them failed
+    PowerLevelsLoaded(UserId, RoomId, Option<PowerLevelsEventContent>),
+    ShowEditHistory(EventId),
+    CloseEditHistory,
+    StartRedaction(EventId),
+    RedactReasonChanged(String),
+    Redact,
+    CancelRedaction,
+    Redacted(EventId),
+    RedactFailed(EventId, String),
+    ShowReactionPicker(EventId),
+    ToggleReaction(EventId, String),
+    ReactionToggled(EventId),
+    ReactionFailed(EventId, String),
+    Reply(EventId),
+    CancelReply,
+    FetchedEvent(RoomId, AnyRoomEvent),
+    FetchEventFailed(RoomId, EventId),
+    OpenLink(String),
+    CopyToClipboard(String),
+    RevealSpoilers(EventId),
+    ShowProfile(UserId),
+    CloseProfile,
+    /// How a user a message links to is shown in a room
+    MemberResolved(UserId, RoomId, UserId, Option<Member>),
+    OpenThread(EventId),
+    CloseThread,
+    /// Loads older replies in the open thread
+    LoadThread,
+    ThreadLoaded(UserId, RoomId, EventId, Vec<AnyRoomEvent>, Option<String>),
+    ThreadLoadFailed(UserId, RoomId, EventId, String),
+    SetThreadMessage(String),
+    SendThreadMessage,
+    ThreadMessageSent,
+    ThreadSendFailed(String),
+    SwitchAccount(UserId),
+    AddAccount,
+    Logout,
+    LoggedOut(UserId),
     SetMessage(String),
     SendMessage,
     RoomMessage(AnyMessageEvent),
@@ -0,0 +1,285 @@
+use iced::{Button, Checkbox, Column, Command, Container, Length, Row, Svg, Text, TextInput};
+
+use crate::{
+    config::Config,
+    matrix::register::{register, registration_flows, Registration},
+    paths::Paths,
+};
+
+use super::{elementary::Elementary, Account, HomePage, LoginPage, Messages, RegisterPage};
+
+impl RegisterPage {
+    pub fn new(
+        paths: Paths,
+        config: Config,
+        homeserver_url: String,
+        accounts: Vec<Account>,
+    ) -> Self {
+        RegisterPage {
+            theme: config.appearance.theme,
+            paths,
+            config,
+            homeserver_url,
+            accounts,
+            ..Default::default()
+        }
+    }
+
+    pub fn update(&mut self, message: Messages) -> (Command<Messages>, Option<Elementary>) {
+        match message {
+            Messages::HomeserverChanged(input) => {
+                self.homeserver_url = input;
+                self.info = None;
+            }
+            Messages::UsernameChanged(input) => self.username = input,
+            Messages::PasswordChanged(input) => self.password = input,
+            Messages::ConfirmPasswordChanged(input) => self.confirm_password = input,
+            Messages::RegistrationTokenChanged(input) => self.token = input,
+            Messages::TermsAccepted(accepted) => self.accepted_terms = accepted,
+            Messages::QueryRegistration => {
+                self.error = None;
+                let homeserver = self.homeserver_url.clone();
+                return (
+                    Command::perform(
+                        async move {
+                            let info = registration_flows(&homeserver).await;
+                            (homeserver, info)
+                        },
+                        |(homeserver, result)| match result {
+                            Ok(info) => Messages::RegistrationFlows(homeserver, info),
+                            Err(e) => Messages::LoginFailed(e.to_string()),
+                        },
+                    ),
+                    None,
+                );
+            }
+            Messages::RegistrationFlows(homeserver, info) => {
+                // Ignore answers for something the user already changed
+                if homeserver == self.homeserver_url {
+                    self.error = if info.supported() {
+                        None
+                    } else {
+                        Some(format!(
+                            "This homeserver requires registration steps we don't support: {}",
+                            info.unsupported.join(", ")
+                        ))
+                    };
+                    self.info = Some(info);
+                }
+            }
+            Messages::Register => {
+                let info = match self.info.clone() {
+                    Some(info) => info,
+                    None => return self.update(Messages::QueryRegistration),
+                };
+                if self.username.trim().is_empty() || self.password.is_empty() {
+                    self.error = Some(String::from("Enter a username and password"));
+                    return (Command::none(), None);
+                }
+                if self.password != self.confirm_password {
+                    self.error = Some(String::from("The passwords don't match"));
+                    return (Command::none(), None);
+                }
+                self.error = None;
+
+                let registration = Registration {
+                    username: self.username.trim().to_owned(),
+                    password: self.password.clone(),
+                    token: self.token.clone(),
+                    accepted_terms: self.accepted_terms,
+                };
+                let paths = self.paths.clone();
+                let device_name = self.config.device.display_name.clone();
+
+                return (
+                    Command::perform(
+                        async move { register(&paths, info, registration, &device_name).await },
+                        |result| match result {
+                            // A new account has no other devices to verify with
+                            Ok((client, session)) => Messages::LoginResult(client, session.into()),
+                            Err(e) => Messages::LoginFailed(e.to_string()),
+                        },
+                    ),
+                    None,
+                );
+            }
+            Messages::LoginResult(client, session) => {
+                let home = HomePage::new(
+                    self.paths.clone(),
+                    self.config.clone(),
+                    self.accounts.clone(),
+                    client,
+                    session,
+                );
+                match home {
+                    Ok(home) => return (home.load_rooms(), Some(Elementary::HomePage(home))),
+                    Err(e) => self.error = Some(format!("{:#}", e)),
+                }
+            }
+            Messages::GoBack => {
+                return (
+                    Command::none(),
+                    Some(Elementary::LoginPage(LoginPage::with_accounts(
+                        self.paths.clone(),
+                        self.config.clone(),
+                        self.accounts.clone(),
+                    ))),
+                );
+            }
+            Messages::LoginFailed(e) => {
+                tracing::warn!("Registration failed: {}", e);
+                self.error = Some(e);
+            }
+            _ => (),
+        };
+
+        (Command::none(), None)
+    }
+
+    pub fn view(&mut self) -> iced::Element<'_, Messages> {
+        let svg = Svg::from_path(format!(
+            "{}/src/resources/matrix-logo.svg",
+            env!("CARGO_MANIFEST_DIR")
+        ));
+
+        let matrix_logo = Container::new(svg)
+            .padding(50)
+            .center_x()
+            .center_y()
+            .height(Length::Fill)
+            .width(Length::FillPortion(1))
+            .style(self.theme);
+
+        let mut form = Column::new().padding(70).spacing(20).push(
+            TextInput::new(
+                &mut self.homeserver_state,
+                "Enter Homeserver (e.g. example.org)...",
+                &self.homeserver_url,
+                Messages::HomeserverChanged,
+            )
+            .on_submit(Messages::QueryRegistration)
+            .size(15)
+            .padding(12)
+            .style(self.theme),
+        );
+
+        match self.info {
+            Some(ref info) if info.supported() => {
+                form = form
+                    .push(
+                        TextInput::new(
+                            &mut self.username_state,
+                            "Choose a Username...",
+                            &self.username,
+                            Messages::UsernameChanged,
+                        )
+                        .size(15)
+                        .padding(12)
+                        .style(self.theme),
+                    )
+                    .push(
+                        TextInput::new(
+                            &mut self.password_state,
+                            "Choose a Password...",
+                            &self.password,
+                            Messages::PasswordChanged,
+                        )
+                        .password()
+                        .size(15)
+                        .padding(12)
+                        .style(self.theme),
+                    )
+                    .push(
+                        TextInput::new(
+                            &mut self.confirm_state,
+                            "Repeat the Password...",
+                            &self.confirm_password,
+                            Messages::ConfirmPasswordChanged,
+                        )
+                        .password()
+                        .size(15)
+                        .padding(12)
+                        .style(self.theme),
+                    );
+
+                if info.needs_token() {
+                    form = form.push(
+                        TextInput::new(
+                            &mut self.token_state,
+                            "Enter Registration Token...",
+                            &self.token,
+                            Messages::RegistrationTokenChanged,
+                        )
+                        .size(15)
+                        .padding(12)
+                        .style(self.theme),
+                    );
+                }
+
+                if info.needs_terms() {
+                    let mut policies = Column::new().spacing(5);
+                    for policy in info.policies.iter() {
+                        policies = policies
+                            .push(Text::new(format!("{}: {}", policy.name, policy.url)).size(15));
+                    }
+                    form = form.push(policies).push(
+                        Checkbox::new(
+                            self.accepted_terms,
+                            "I accept the terms of the homeserver",
+                            Messages::TermsAccepted,
+                        )
+                        .style(self.theme),
+                    );
+                }
+
+                form = form.push(
+                    Button::new(
+                        &mut self.register_button_state,
+                        Text::new("Register")
+                            .horizontal_alignment(iced::HorizontalAlignment::Center),
+                    )
+                    .width(Length::Fill)
+                    .style(self.theme)
+                    .on_press(Messages::Register),
+                );
+            }
+            _ => {
+                form = form.push(
+                    Button::new(
+                        &mut self.check_button_state,
+                        Text::new("Continue")
+                            .horizontal_alignment(iced::HorizontalAlignment::Center),
+                    )
+                    .width(Length::Fill)
+                    .style(self.theme)
+                    .on_press(Messages::QueryRegistration),
+                );
+            }
+        }
+
+        if let Some(ref error) = self.error {
+            form = form.push(Text::new(error).size(15).color([1.0, 0.4, 0.4]));
+        }
+
+        form = form.push(
+            Button::new(
+                &mut self.back_button_state,
+                Text::new("Back").horizontal_alignment(iced::HorizontalAlignment::Center),
+            )
+            .width(Length::Fill)
+            .style(self.theme)
+            .on_press(Messages::GoBack),
+        );
+
+        let register_form = Container::new(form)
+            .height(Length::Fill)
+            .width(Length::FillPortion(1))
+            .style(self.theme)
+            .center_y();
+
+        Container::new(Row::new().push(matrix_logo).push(register_form))
+            .height(Length::Fill)
+            .style(self.theme)
+            .into()
+    }
+}
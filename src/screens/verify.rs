@@ -18,7 +18,7 @@ impl<T> EmojiHandler<T> {
     }
 }
 
-const ICONS: Font = Font::External {
+pub(super) const ICONS: Font = Font::External {
     name: "Icons",
     bytes: include_bytes!("/usr/share/fonts/Unifont/Unifont.ttf"),
 };
@@ -26,8 +26,21 @@ const ICONS: Font = Font::External {
 impl VerifyPage {
     pub fn update(&mut self, message: super::Messages) -> (Command<Messages>, Option<Elementary>) {
         match message {
-            Messages::Sync(event) => match event {
-                crate::matrix::subscriber::MatrixEvents::Room(_) => {}
+            Messages::Sync(_, event) => match event {
+                crate::matrix::subscriber::MatrixEvents::LoggedOut => {
+                    let paths = self.paths.clone();
+                    let session = self.session.clone();
+                    return (
+                        async move {
+                            match crate::matrix::login::forget_session(&paths, &session) {
+                                Ok(_) => Messages::GoBack,
+                                Err(e) => Messages::LoginFailed(e.to_string()),
+                            }
+                        }
+                        .into(),
+                        None,
+                    );
+                }
                 crate::matrix::subscriber::MatrixEvents::ToDevice(device_event) => {
                     match device_event {
                         matrix_sdk::events::AnyToDeviceEvent::Dummy(_) => {}
@@ -79,6 +92,7 @@ impl VerifyPage {
                         matrix_sdk::events::AnyToDeviceEvent::Custom(_) => {}
                     };
                 }
+                _ => {}
             },
             Messages::SetVerification(verification_content, sas) => {
                 self.verification_emoji = verification_content;
@@ -111,18 +125,34 @@ impl VerifyPage {
                 }
             }
             Messages::GoHome => {
-                return (
-                    Command::none(),
-                    Some(Elementary::HomePage(HomePage::new(
-                        self.client.clone(),
-                        self.session.clone(),
-                    ))),
+                let home = HomePage::new(
+                    self.paths.clone(),
+                    self.config.clone(),
+                    self.accounts.clone(),
+                    self.client.clone(),
+                    self.session.clone(),
                 );
+                match home {
+                    Ok(home) => return (home.load_rooms(), Some(Elementary::HomePage(home))),
+                    Err(e) => {
+                        let mut login = LoginPage::with_accounts(
+                            self.paths.clone(),
+                            self.config.clone(),
+                            self.accounts.clone(),
+                        );
+                        login.error = Some(format!("{:#}", e));
+                        return (Command::none(), Some(Elementary::LoginPage(login)));
+                    }
+                }
             }
             Messages::GoBack => {
                 return (
                     Command::none(),
-                    Some(Elementary::LoginPage(LoginPage::default())),
+                    Some(Elementary::LoginPage(LoginPage::with_accounts(
+                        self.paths.clone(),
+                        self.config.clone(),
+                        self.accounts.clone(),
+                    ))),
                 );
             }
             _ => {}
@@ -1,8 +1,9 @@
-use std::io;
-
+use diesel::SqliteConnection;
 use matrix_sdk::identifiers::{DeviceId, UserId};
 use serde::{Deserialize, Serialize};
 
+use crate::{database::session::add_session, paths::Paths};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Session {
     pub access_token: String,
@@ -21,22 +22,29 @@ impl From<Session> for matrix_sdk::Session {
     }
 }
 
-fn session_path() -> std::path::PathBuf {
-    std::path::PathBuf::from("./data/config/session.toml")
-}
-
-pub fn write_session(session: &Session) -> Result<(), io::Error> {
-    let serialized = toml::to_string(&session)
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
-    std::fs::write(session_path(), serialized)?;
-    Ok(())
+/// Sessions as they used to be stored in the TOML file
+#[derive(Debug, Clone, Default, Deserialize)]
+struct TomlSessions {
+    accounts: Vec<Session>,
 }
 
-pub fn get_session() -> Result<Option<Session>, io::Error> {
-    let path = session_path();
-    if !path.is_file() {
-        return Ok(None);
+/// Moves the sessions of the old TOML session file into the database.
+///
+/// The file is removed once every session has been stored, so this only does something once.
+pub fn import_toml_sessions(paths: &Paths, conn: &SqliteConnection) -> Result<(), anyhow::Error> {
+    let path = match paths.legacy_session_file() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let contents = std::fs::read(&path)?;
+    // The oldest versions stored a single session at the top level of the file
+    let sessions = match toml::from_slice::<Session>(&contents) {
+        Ok(session) => vec![session],
+        Err(_) => toml::from_slice::<TomlSessions>(&contents)?.accounts,
+    };
+    for session in sessions.iter() {
+        add_session(conn, session)?;
     }
-    let session: Session = toml::from_slice(&std::fs::read(path)?)?;
-    Ok(Some(session))
+    std::fs::remove_file(path)?;
+    Ok(())
 }
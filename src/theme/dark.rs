@@ -26,6 +26,9 @@ const HOVERED: Color = Color::from_rgb(
     0xC4 as f32 / 255.0,
 );
 
+/// Highlighting theme of code blocks, bundled with syntect
+pub const CODE_THEME: &str = "base16-ocean.dark";
+
 pub struct Container;
 
 impl container::StyleSheet for Container {
@@ -50,6 +53,19 @@ impl container::StyleSheet for RoomContainer {
     }
 }
 
+pub struct CodeBlock;
+
+impl container::StyleSheet for CodeBlock {
+    fn style(&self) -> container::Style {
+        container::Style {
+            background: Color::from_rgb8(0x2B, 0x30, 0x3B).into(),
+            text_color: Color::from_rgb8(0xC0, 0xC5, 0xCE).into(),
+            border_radius: 4.0,
+            ..container::Style::default()
+        }
+    }
+}
+
 pub struct Radio;
 
 impl radio::StyleSheet for Radio {
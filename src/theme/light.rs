@@ -1,4 +1,7 @@
-use iced::{button, Color, Vector};
+use iced::{button, container, Color, Vector};
+
+/// Highlighting theme of code blocks, bundled with syntect
+pub const CODE_THEME: &str = "InspiredGitHub";
 
 pub struct Button;
 
@@ -21,3 +24,17 @@ impl button::StyleSheet for Button {
         }
     }
 }
+
+pub struct CodeBlock;
+
+impl container::StyleSheet for CodeBlock {
+    fn style(&self) -> container::Style {
+        container::Style {
+            background: Color::from_rgb8(0xF5, 0xF5, 0xF5).into(),
+            text_color: Color::from_rgb8(0x32, 0x32, 0x32).into(),
+            border_radius: 4.0,
+            border_width: 1.0,
+            border_color: Color::from_rgb8(0xDD, 0xDD, 0xDD),
+        }
+    }
+}
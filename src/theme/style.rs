@@ -2,17 +2,37 @@ use iced::{
     button, checkbox, container, progress_bar, radio, rule, scrollable, slider, text_input,
 };
 
+use serde::Deserialize;
+
 use super::{dark, light};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Theme {
     Light,
     Dark,
+    #[serde(skip)]
     DarkRoom,
 }
 
 impl Theme {
     pub const ALL: [Theme; 2] = [Theme::Light, Theme::Dark];
+
+    /// Name of the bundled syntect theme code is highlighted with
+    pub fn code_theme(self) -> &'static str {
+        match self {
+            Theme::Light => light::CODE_THEME,
+            Theme::Dark | Theme::DarkRoom => dark::CODE_THEME,
+        }
+    }
+
+    /// Style of the box around code blocks
+    pub fn code_block(self) -> Box<dyn container::StyleSheet> {
+        match self {
+            Theme::Light => light::CodeBlock.into(),
+            Theme::Dark | Theme::DarkRoom => dark::CodeBlock.into(),
+        }
+    }
 }
 
 impl Default for Theme {
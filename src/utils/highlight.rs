@@ -0,0 +1,55 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+
+use once_cell::sync::Lazy;
+use syntect::{
+    easy::HighlightLines, highlighting::ThemeSet, parsing::SyntaxSet, util::LinesWithEndings,
+};
+
+static SYNTAXES: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+static THEMES: Lazy<ThemeSet> = Lazy::new(ThemeSet::load_defaults);
+/// Code we've highlighted already by code, language and theme, since it's drawn every frame
+static HIGHLIGHTED: Lazy<Mutex<HashMap<(String, String, String), Option<Vec<Line>>>>> =
+    Lazy::new(Default::default);
+/// The cache is emptied once it holds this many code blocks
+const CACHE_SIZE: usize = 256;
+
+/// Runs of text in a line of code with their RGB color
+pub type Line = Vec<([u8; 3], String)>;
+
+/// Highlights code in `language` with one of the bundled themes, `None` if we don't know the
+/// language
+pub fn highlight(code: &str, language: Option<&str>, theme: &str) -> Option<Vec<Line>> {
+    let key = (code.to_owned(), language?.to_owned(), theme.to_owned());
+    let mut cache = HIGHLIGHTED.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(lines) = cache.get(&key) {
+        return lines.clone();
+    }
+    let lines = highlight_lines(&key.0, &key.1, &key.2);
+    if cache.len() >= CACHE_SIZE {
+        cache.clear();
+    }
+    cache.insert(key, lines.clone());
+    lines
+}
+
+fn highlight_lines(code: &str, language: &str, theme: &str) -> Option<Vec<Line>> {
+    let syntax = SYNTAXES.find_syntax_by_token(language)?;
+    let theme = THEMES.themes.get(theme)?;
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let lines = LinesWithEndings::from(code)
+        .map(|line| {
+            highlighter
+                .highlight(line, &SYNTAXES)
+                .into_iter()
+                .map(|(style, text)| {
+                    let color = [style.foreground.r, style.foreground.g, style.foreground.b];
+                    (color, text.trim_end_matches('\n').to_owned())
+                })
+                .collect()
+        })
+        .collect();
+    Some(lines)
+}
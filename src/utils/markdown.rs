@@ -1,69 +1,345 @@
 use comrak::{
-    nodes::{AstNode, NodeValue},
+    format_html,
+    nodes::{AstNode, ListType, NodeValue},
     parse_document, Arena, ComrakOptions,
 };
-use iced::Text;
 
-pub fn parse_text(content: String) {
+use crate::rich_text::{push_span, safe_link, Block, Span, Style};
+
+/// The Markdown extensions we support, on top of CommonMark
+pub fn options() -> ComrakOptions {
+    let mut options = ComrakOptions::default();
+    options.extension.strikethrough = true;
+    options.extension.table = true;
+    options.extension.autolink = true;
+    options
+}
+
+/// Renders Markdown into blocks for display
+pub fn parse_text(content: &str) -> Vec<Block> {
     let arena = Arena::new();
-    let root = parse_document(&arena, &content, &ComrakOptions::default());
+    let root = parse_document(&arena, content, &options());
+    blocks(root)
+}
+
+/// Renders Markdown into the HTML of a formatted body, `None` if there is no formatting and the
+/// text can be sent as is. Raw HTML in the text is left out
+pub fn to_html(content: &str) -> Option<String> {
+    let arena = Arena::new();
+    let options = options();
+    let root = parse_document(&arena, content, &options);
+    let plain = root.children().count() <= 1
+        && root.descendants().all(|node| {
+            matches!(
+                node.data.borrow().value,
+                NodeValue::Document
+                    | NodeValue::Paragraph
+                    | NodeValue::Text(_)
+                    | NodeValue::SoftBreak
+            )
+        });
+    if plain {
+        return None;
+    }
+
+    let mut html = Vec::new();
+    format_html(root, &options, &mut html).ok()?;
+    let html = String::from_utf8(html).ok()?;
+    let html = html.trim_end();
+    // A single paragraph doesn't need to be wrapped
+    let single_paragraph = root.children().count() == 1
+        && root.first_child().map_or(false, |node| {
+            matches!(node.data.borrow().value, NodeValue::Paragraph)
+        });
+    let html = match (single_paragraph, html.strip_prefix("<p>")) {
+        (true, Some(inner)) => inner.strip_suffix("</p>").unwrap_or(inner),
+        _ => html,
+    };
+    Some(html.to_owned())
+}
+
+/// The plain and formatted body of a message draft. `/plain` sends the rest of the draft as
+/// typed
+pub fn compose(draft: &str) -> (String, Option<String>) {
+    match draft.strip_prefix("/plain ") {
+        Some(text) => (text.to_owned(), None),
+        None => (draft.to_owned(), to_html(draft)),
+    }
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
 
-    let message_contents = iter_nodes(root, &|node, mut message_contents| {
-        match node.data.clone().into_inner().value {
-            NodeValue::Text(text) => {
-                message_contents.push(String::from_utf8(text).unwrap());
+/// The blocks a container node is made of
+fn blocks<'a>(node: &'a AstNode<'a>) -> Vec<Block> {
+    node.children().filter_map(block).collect()
+}
+
+fn block<'a>(node: &'a AstNode<'a>) -> Option<Block> {
+    let block = match node.data.borrow().value {
+        NodeValue::Paragraph => Block::Paragraph(spans(node)),
+        NodeValue::Heading(ref heading) => Block::Heading(heading.level as u8, spans(node)),
+        NodeValue::CodeBlock(ref code_block) => {
+            let info = text(&code_block.info);
+            Block::Code {
+                language: info.split_whitespace().next().map(ToOwned::to_owned),
+                code: text(&code_block.literal).trim_end_matches('\n').to_owned(),
+            }
+        }
+        NodeValue::BlockQuote => Block::Quote(blocks(node)),
+        NodeValue::List(ref list) => Block::List {
+            start: match list.list_type {
+                ListType::Ordered => Some(list.start as u64),
+                ListType::Bullet => None,
+            },
+            items: node.children().map(blocks).collect(),
+        },
+        NodeValue::Table(_) => Block::Table {
+            header: node.first_child().map_or(false, |row| {
+                matches!(row.data.borrow().value, NodeValue::TableRow(true))
+            }),
+            rows: node
+                .children()
+                .map(|row| row.children().map(spans).collect())
+                .collect(),
+        },
+        NodeValue::ThematicBreak => Block::Rule,
+        // HTML in a plain body is shown as typed
+        NodeValue::HtmlBlock(ref html) => Block::Paragraph(vec![Span {
+            text: text(&html.literal).trim_end().to_owned(),
+            style: Style::default(),
+        }]),
+        _ => return None,
+    };
+    Some(block)
+}
+
+/// The formatted text of a node
+fn spans<'a>(node: &'a AstNode<'a>) -> Vec<Span> {
+    let mut spans = Vec::new();
+    inline(node, &Style::default(), &mut spans);
+    spans
+}
+
+fn inline<'a>(node: &'a AstNode<'a>, style: &Style, spans: &mut Vec<Span>) {
+    for child in node.children() {
+        let mut style = style.clone();
+        match child.data.borrow().value {
+            NodeValue::Text(ref literal) | NodeValue::HtmlInline(ref literal) => {
+                push_span(spans, &text(literal), &style);
+                continue;
             }
-            NodeValue::Document => {}
-            NodeValue::BlockQuote => {}
-            NodeValue::List(_) => {}
-            NodeValue::Item(_) => {}
-            NodeValue::DescriptionList => {}
-            NodeValue::DescriptionItem(_) => {}
-            NodeValue::DescriptionTerm => {}
-            NodeValue::DescriptionDetails => {}
-            NodeValue::CodeBlock(code_block) => {
-                if code_block.fenced {
-                    if code_block.fence_length == 3 {
-                        message_contents.push(String::from_utf8(code_block.literal).unwrap());
-                    } else {
-                        message_contents[message_contents.len() - 1]
-                    }
+            NodeValue::Code(ref literal) => {
+                style.code = true;
+                push_span(spans, &text(literal), &style);
+                continue;
+            }
+            // Spans can't break lines
+            NodeValue::SoftBreak | NodeValue::LineBreak => {
+                push_span(spans, " ", &style);
+                continue;
+            }
+            NodeValue::Emph => style.italic = true,
+            NodeValue::Strong => style.bold = true,
+            NodeValue::Strikethrough => style.strikethrough = true,
+            NodeValue::Link(ref link) => {
+                let url = text(&link.url);
+                if safe_link(&url) {
+                    style.link = Some(url);
                 }
             }
-            NodeValue::HtmlBlock(_) => {}
-            NodeValue::Paragraph => {}
-            NodeValue::Heading(_) => {}
-            NodeValue::ThematicBreak => {}
-            NodeValue::FootnoteDefinition(_) => {}
-            NodeValue::Table(_) => {}
-            NodeValue::TableRow(_) => {}
-            NodeValue::TableCell => {}
-            NodeValue::TaskItem(_) => {}
-            NodeValue::SoftBreak => {}
-            NodeValue::LineBreak => {}
-            NodeValue::Code(_) => {}
-            NodeValue::HtmlInline(_) => {}
-            NodeValue::Emph => {}
-            NodeValue::Strong => {}
-            NodeValue::Strikethrough => {}
-            NodeValue::Superscript => {}
-            NodeValue::Link(_) => {}
-            NodeValue::Image(_) => {}
-            NodeValue::FootnoteReference(_) => {}
-        };
-        message_contents
-    });
+            _ => {}
+        }
+        inline(child, &style, spans);
+    }
 }
 
-fn iter_nodes<'a, F>(node: &'a AstNode<'a>, element_builder: &F) -> Vec<String>
-where
-    F: Fn(&'a AstNode<'a>, Vec<String>) -> Vec<String>,
-{
-    let mut message_contents = Vec::new();
-    message_contents = element_builder(node, message_contents);
-    for c in node.children() {
-        message_contents = iter_nodes(c, element_builder);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(text: &str, style: Style) -> Span {
+        Span {
+            text: text.to_owned(),
+            style,
+        }
+    }
+
+    fn paragraph(text: &str) -> Block {
+        Block::Paragraph(vec![span(text, Style::default())])
+    }
+
+    #[test]
+    fn emphasis() {
+        let italic = Style {
+            italic: true,
+            ..Default::default()
+        };
+        let bold = Style {
+            bold: true,
+            ..Default::default()
+        };
+        let strikethrough = Style {
+            strikethrough: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_text("*a* _b_ **c** ~~d~~"),
+            vec![Block::Paragraph(vec![
+                span("a", italic.clone()),
+                span(" ", Style::default()),
+                span("b", italic),
+                span(" ", Style::default()),
+                span("c", bold),
+                span(" ", Style::default()),
+                span("d", strikethrough),
+            ])]
+        );
+    }
+
+    #[test]
+    fn inline_code() {
+        let code = Style {
+            code: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_text("call `main()` first"),
+            vec![Block::Paragraph(vec![
+                span("call ", Style::default()),
+                span("main()", code),
+                span(" first", Style::default()),
+            ])]
+        );
+    }
+
+    #[test]
+    fn links_and_headings() {
+        let link = Style {
+            link: Some(String::from("https://example.org")),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_text("# Title\n\nsee https://example.org"),
+            vec![
+                Block::Heading(1, vec![span("Title", Style::default())]),
+                Block::Paragraph(vec![
+                    span("see ", Style::default()),
+                    span("https://example.org", link),
+                ]),
+            ]
+        );
+        assert_eq!(
+            parse_text("[text](javascript:alert(1))"),
+            vec![paragraph("text")]
+        );
+    }
+
+    #[test]
+    fn fenced_code_keeps_its_language() {
+        assert_eq!(
+            parse_text("```rust\nfn main() {\n    println!();\n}\n```"),
+            vec![Block::Code {
+                language: Some(String::from("rust")),
+                code: String::from("fn main() {\n    println!();\n}"),
+            }]
+        );
+        assert_eq!(
+            parse_text("```\nplain\n```"),
+            vec![Block::Code {
+                language: None,
+                code: String::from("plain"),
+            }]
+        );
     }
 
-    message_contents
+    #[test]
+    fn lists_keep_their_start() {
+        assert_eq!(
+            parse_text("3. three\n4. four"),
+            vec![Block::List {
+                start: Some(3),
+                items: vec![vec![paragraph("three")], vec![paragraph("four")]],
+            }]
+        );
+        assert_eq!(
+            parse_text("- one\n- two"),
+            vec![Block::List {
+                start: None,
+                items: vec![vec![paragraph("one")], vec![paragraph("two")]],
+            }]
+        );
+    }
+
+    #[test]
+    fn quotes_join_their_lines() {
+        assert_eq!(
+            parse_text("> quoted\n> text"),
+            vec![Block::Quote(vec![paragraph("quoted text")])]
+        );
+    }
+
+    #[test]
+    fn tables_have_a_header() {
+        assert_eq!(
+            parse_text("| a | b |\n|---|---|\n| 1 | 2 |"),
+            vec![Block::Table {
+                header: true,
+                rows: vec![
+                    vec![
+                        vec![span("a", Style::default())],
+                        vec![span("b", Style::default())]
+                    ],
+                    vec![
+                        vec![span("1", Style::default())],
+                        vec![span("2", Style::default())]
+                    ],
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(
+            parse_text("above\n\n---\n\nbelow"),
+            vec![paragraph("above"), Block::Rule, paragraph("below")]
+        );
+    }
+
+    #[test]
+    fn plain_text_is_sent_without_html() {
+        assert_eq!(to_html("just text"), None);
+        assert_eq!(to_html("two\nlines"), None);
+        assert_eq!(to_html("*a*"), Some(String::from("<em>a</em>")));
+        assert_eq!(
+            to_html("one\n\ntwo"),
+            Some(String::from("<p>one</p>\n<p>two</p>"))
+        );
+    }
+
+    #[test]
+    fn formatted_drafts_have_html() {
+        assert_eq!(
+            compose("**bold** move"),
+            (
+                String::from("**bold** move"),
+                Some(String::from("<strong>bold</strong> move"))
+            )
+        );
+        assert_eq!(compose("hello"), (String::from("hello"), None));
+        assert_eq!(compose("/plain **bold**"), (String::from("**bold**"), None));
+    }
+
+    #[test]
+    fn raw_html_is_left_out() {
+        let (text, html) = compose("*hi* <script>alert(1)</script>");
+        assert_eq!(text, "*hi* <script>alert(1)</script>");
+        let html = html.unwrap();
+        assert!(html.starts_with("<em>hi</em>"));
+        assert!(!html.contains("<script>"));
+        let (_, html) = compose("<b>raw</b>\n\n# Title");
+        assert!(!html.unwrap().contains("<b>"));
+    }
 }